@@ -1,87 +1,125 @@
+use std::io::{self, Write};
+use std::mem;
+
 use crate::op::Op;
+use crate::scanner::Span;
 use crate::value::Value;
 
-struct LineData {
-    ops: usize,
-    line: usize,
+#[derive(Debug)]
+// Tracks bytes rather than ops, so that a span can be found from nothing
+// but an instruction's position, even once jumps break up the op sequence.
+struct SpanData {
+    bytes: usize,
+    span: Span,
 }
 
-impl LineData {
-    fn new(line: usize) -> LineData {
-        LineData { line, ops: 1 }
+impl SpanData {
+    fn new(span: Span, bytes: usize) -> SpanData {
+        SpanData { span, bytes }
     }
 
-    fn tick(&mut self) {
-        self.ops += 1;
+    fn tick(&mut self, bytes: usize) {
+        self.bytes += bytes;
     }
 }
 
-#[derive(Default)]
+#[derive(Debug, Default)]
 pub struct Chunk {
     code: Vec<u8>,
     values: Vec<Value>,
-    lines: Vec<LineData>,
+    spans: Vec<SpanData>,
 }
 
 impl Chunk {
-    pub fn of(f: fn(&mut Chunk) -> ()) -> Chunk {
-        let mut new = Chunk::default();
-        f(&mut new);
-        new
-    }
-
     #[inline]
     pub fn code_ptr(&self) -> *const u8 {
         self.code.as_ptr()
     }
 
+    /// The number of bytes of code written so far, i.e. the position of the
+    /// next instruction.
+    pub fn code_len(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Overwrite the offset of the jump instruction starting at `op_pos`.
+    pub fn patch_jump(&mut self, op_pos: usize, offset: u16) {
+        self.code[op_pos + 1..op_pos + 3].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    /// Roughly how many bytes the chunk's code, constants and spans take up.
+    pub fn size(&self) -> usize {
+        self.code.capacity()
+            + self.values.capacity() * mem::size_of::<Value>()
+            + self.spans.capacity() * mem::size_of::<SpanData>()
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.values
+    }
+
     #[inline]
     pub fn get_constant(&self, val_index: usize) -> &Value {
         &self.values[val_index]
     }
 
-    pub fn operation(&mut self, op: Op, op_line: usize) {
+    /// Write an instruction, attributing it to the source in `op_span`.
+    pub fn operation(&mut self, op: Op, op_span: Span) {
         op.write_to(&mut self.code);
-        match self.lines.last_mut() {
-            None => self.lines.push(LineData::new(op_line)),
-            Some(last_line) => {
-                if last_line.line == op_line {
-                    last_line.tick();
+        let bytes = op.cost();
+        match self.spans.last_mut() {
+            None => self.spans.push(SpanData::new(op_span, bytes)),
+            Some(last_span) => {
+                if last_span.span == op_span {
+                    last_span.tick(bytes);
                 } else {
-                    self.lines.push(LineData::new(op_line));
+                    self.spans.push(SpanData::new(op_span, bytes));
                 }
             }
         };
     }
 
-    /// Store and add a retrieve instruction for a constant.
-    /// Useful for early tests but I should nuke it some time.
-    pub fn push_const(&mut self, value: Value, line: usize) {
+    /// Store a constant without emitting any instruction, returning its index.
+    pub fn add_constant(&mut self, value: Value) -> usize {
         let val_index = self.values.len();
         self.values.push(value);
-        self.operation(Op::Const(val_index), line);
+        val_index
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("== {:^27} ==", name);
+    /// Store and add a retrieve instruction for a constant.
+    /// Useful for early tests but I should nuke it some time.
+    pub fn push_const(&mut self, value: Value, span: Span) {
+        let val_index = self.add_constant(value);
+        self.operation(Op::Const(val_index), span);
+    }
+
+    pub fn disassemble(&self, out: &mut dyn Write, name: &str) -> io::Result<()> {
+        writeln!(out, "== {:^27} ==", name)?;
 
         let ops = Op::read_all(&self.code);
         let mut pos: usize = 0;
         // TODO: figure out stateful iterators
-        for (op_index, op) in ops.iter().enumerate() {
-            op.print(self, op_index, pos);
+        for op in ops.iter() {
+            op.print(out, self, pos)?;
             pos += op.cost();
         }
+        Ok(())
     }
 
-    pub fn get_line(&self, op_index: usize) -> usize {
-        let mut op_count = 0_usize;
-        for LineData { ops, line } in &self.lines {
-            op_count += *ops;
-            if op_index < op_count {
-                return *line;
+    /// Get the source span of the instruction at (or spanning) byte `pos`.
+    pub fn get_span(&self, pos: usize) -> Span {
+        let mut byte_count = 0_usize;
+        for SpanData { bytes, span } in &self.spans {
+            byte_count += *bytes;
+            if pos < byte_count {
+                return *span;
             }
         }
-        panic!("Corrupt line data");
+        panic!("Corrupt span data");
+    }
+
+    /// Get the source line of the instruction at (or spanning) byte `pos`.
+    pub fn get_line(&self, pos: usize) -> usize {
+        self.get_span(pos).start.line
     }
 }
@@ -1,25 +1,181 @@
 use core::panic;
-use std::mem;
+use std::fmt::{self, Display, Formatter};
+use std::io::Write;
+use std::{convert::TryFrom, mem};
 
-use broom::Heap;
+use broom::Handle;
 
+use crate::gc::Heap;
 use crate::{
     chunk::Chunk,
-    op::Op,
-    scanner::{CodePosition, ScanError, Scanner, Token, TokenType},
-    value::{Object, Value},
+    data::u24,
+    op::{Op, JUMP_COST},
+    scanner::{ScanError, Scanner, SourceId, Span, Token, TokenType},
+    value::{Class, Function, Object, UpvalueRef, Value},
 };
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum SyntaxError {
-    UnexpectedEOF,
-    ExpectedPrefix,
-    ExpectedInfix,
+    UnexpectedEOF {
+        span: Span,
+    },
+    ExpectedPrefix {
+        span: Span,
+    },
+    ExpectedInfix {
+        span: Span,
+    },
     UnexpectedToken {
-        pos: CodePosition,
+        span: Span,
         expected: TokenType,
         actual: TokenType,
     },
+    InvalidAssignmentTarget {
+        span: Span,
+    },
+    SelfReferentialInitializer {
+        span: Span,
+        name: String,
+    },
+    DuplicateDeclaration {
+        span: Span,
+        name: String,
+    },
+    TooManyLocals {
+        span: Span,
+    },
+    JumpTooLarge {
+        span: Span,
+    },
+    TooManyParameters {
+        span: Span,
+    },
+    TooManyArguments {
+        span: Span,
+    },
+    ReturnFromTopLevel {
+        span: Span,
+    },
+    TooManyUpvalues {
+        span: Span,
+    },
+    ThisOutsideClass {
+        span: Span,
+    },
+    ReturnValueFromInitializer {
+        span: Span,
+    },
+    InheritFromSelf {
+        span: Span,
+        name: String,
+    },
+    SuperOutsideClass {
+        span: Span,
+    },
+    SuperWithoutSuperclass {
+        span: Span,
+    },
+}
+
+impl SyntaxError {
+    /// The source the error is blamed on.
+    pub fn span(&self) -> Span {
+        match self {
+            Self::UnexpectedEOF { span }
+            | Self::ExpectedPrefix { span }
+            | Self::ExpectedInfix { span }
+            | Self::UnexpectedToken { span, .. }
+            | Self::InvalidAssignmentTarget { span }
+            | Self::SelfReferentialInitializer { span, .. }
+            | Self::DuplicateDeclaration { span, .. }
+            | Self::TooManyLocals { span }
+            | Self::JumpTooLarge { span }
+            | Self::TooManyParameters { span }
+            | Self::TooManyArguments { span }
+            | Self::ReturnFromTopLevel { span }
+            | Self::TooManyUpvalues { span }
+            | Self::ThisOutsideClass { span }
+            | Self::ReturnValueFromInitializer { span }
+            | Self::InheritFromSelf { span, .. }
+            | Self::SuperOutsideClass { span }
+            | Self::SuperWithoutSuperclass { span } => *span,
+        }
+    }
+}
+
+impl SyntaxError {
+    /// The error's `E01xx` code, as described on `Diagnostic::code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnexpectedEOF { .. } => "E0100",
+            Self::ExpectedPrefix { .. } => "E0101",
+            Self::ExpectedInfix { .. } => "E0102",
+            Self::UnexpectedToken { .. } => "E0103",
+            Self::InvalidAssignmentTarget { .. } => "E0104",
+            Self::SelfReferentialInitializer { .. } => "E0105",
+            Self::DuplicateDeclaration { .. } => "E0106",
+            Self::TooManyLocals { .. } => "E0107",
+            Self::JumpTooLarge { .. } => "E0108",
+            Self::TooManyParameters { .. } => "E0109",
+            Self::TooManyArguments { .. } => "E0110",
+            Self::ReturnFromTopLevel { .. } => "E0111",
+            Self::TooManyUpvalues { .. } => "E0112",
+            Self::ThisOutsideClass { .. } => "E0113",
+            Self::ReturnValueFromInitializer { .. } => "E0114",
+            Self::InheritFromSelf { .. } => "E0115",
+            Self::SuperOutsideClass { .. } => "E0116",
+            Self::SuperWithoutSuperclass { .. } => "E0117",
+        }
+    }
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEOF { .. } => write!(f, "Unexpected end of input"),
+            Self::ExpectedPrefix { .. } => write!(f, "Expected an expression"),
+            Self::ExpectedInfix { .. } => write!(f, "Expected an operator"),
+            Self::UnexpectedToken {
+                expected, actual, ..
+            } => write!(f, "Expected '{}' but found '{}'", expected, actual),
+            Self::InvalidAssignmentTarget { .. } => write!(f, "Invalid assignment target"),
+            Self::SelfReferentialInitializer { name, .. } => {
+                write!(
+                    f,
+                    "Can't read local variable '{}' in its own initializer",
+                    name
+                )
+            }
+            Self::DuplicateDeclaration { name, .. } => {
+                write!(
+                    f,
+                    "A variable named '{}' is already declared in this scope",
+                    name
+                )
+            }
+            Self::TooManyLocals { .. } => write!(f, "Too many local variables in function"),
+            Self::JumpTooLarge { .. } => write!(f, "Too much code to jump over"),
+            Self::TooManyParameters { .. } => {
+                write!(f, "Can't have more than {} parameters", u8::MAX)
+            }
+            Self::TooManyArguments { .. } => {
+                write!(f, "Can't have more than {} arguments", u8::MAX)
+            }
+            Self::ReturnFromTopLevel { .. } => write!(f, "Can't return from top-level code"),
+            Self::TooManyUpvalues { .. } => write!(f, "Too many closure variables in function"),
+            Self::ThisOutsideClass { .. } => write!(f, "Can't use 'this' outside of a class"),
+            Self::ReturnValueFromInitializer { .. } => {
+                write!(f, "Can't return a value from an initializer")
+            }
+            Self::InheritFromSelf { name, .. } => {
+                write!(f, "Class '{}' can't inherit from itself", name)
+            }
+            Self::SuperOutsideClass { .. } => write!(f, "Can't use 'super' outside of a class"),
+            Self::SuperWithoutSuperclass { .. } => {
+                write!(f, "Can't use 'super' in a class with no superclass")
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -29,6 +185,36 @@ pub enum CompileError {
     Internal(String),
 }
 
+impl CompileError {
+    /// The source the error is blamed on, if any.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::Scan(e) => Some(e.span),
+            Self::Syntax(e) => Some(e.span()),
+            Self::Internal(_) => None,
+        }
+    }
+
+    /// The code of the scan or syntax error, or `E0900` for an internal one.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Scan(e) => e.code(),
+            Self::Syntax(e) => e.code(),
+            Self::Internal(_) => "E0900",
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scan(e) => e.fmt(f),
+            Self::Syntax(e) => e.fmt(f),
+            Self::Internal(message) => write!(f, "Internal compiler error: {}", message),
+        }
+    }
+}
+
 impl From<SyntaxError> for CompileError {
     fn from(e: SyntaxError) -> Self {
         CompileError::Syntax(e)
@@ -43,41 +229,190 @@ impl From<ScanError> for CompileError {
 
 type CompileResult<A> = Result<A, CompileError>;
 
-pub struct Compiler<'s> {
+/// Locals are addressed by a single byte operand.
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
+/// As are upvalues.
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+
+struct Local<'s> {
+    name: &'s str,
+    /// `None` while the local's initializer is still being compiled.
+    depth: Option<usize>,
+    /// Whether a closure refers to this local, in which case it must be moved
+    /// off the stack when it goes out of scope.
+    is_captured: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+/// The state of a single function body being compiled. Function declarations
+/// nest, so the compiler keeps a stack of these.
+struct FunctionFrame<'s> {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local<'s>>,
+    scope_depth: usize,
+}
+
+impl<'s> FunctionFrame<'s> {
+    fn new(kind: FunctionKind, name: Option<String>, source: SourceId) -> FunctionFrame<'s> {
+        FunctionFrame {
+            function: Function {
+                name,
+                source,
+                ..Default::default()
+            },
+            kind,
+            // Slot zero holds the function being called, or the receiver of a method.
+            locals: vec![Local {
+                name: match kind {
+                    FunctionKind::Method | FunctionKind::Initializer => "this",
+                    FunctionKind::Script | FunctionKind::Function => "",
+                },
+                depth: Some(0),
+                is_captured: false,
+            }],
+            scope_depth: 0,
+        }
+    }
+}
+
+struct ClassFrame {
+    has_superclass: bool,
+}
+
+pub struct Compiler<'s, 'h> {
     // TODO: look into peekable
     scanner: Scanner<'s>,
     previous: Option<Token>,
     current: Option<Token>,
-    chunk: Chunk,
-    heap: Heap<Object>,
+    heap: &'h mut Heap,
+    frames: Vec<FunctionFrame<'s>>,
+    /// The class declarations enclosing the code being compiled, innermost last.
+    classes: Vec<ClassFrame>,
+    errors: Vec<CompileError>,
+    /// Whether a trailing expression statement returns its value from the
+    /// script, for the REPL to show.
+    repl: bool,
+    /// Where to print each function's code once it's compiled, if anywhere.
+    code_sink: Option<&'h mut dyn Write>,
+    source: SourceId,
 }
 
-impl<'s> Compiler<'s> {
-    fn new(src: &'s str) -> Compiler<'s> {
+impl<'s, 'h> Compiler<'s, 'h> {
+    fn new(
+        src: &'s str,
+        source: SourceId,
+        heap: &'h mut Heap,
+        repl: bool,
+        code_sink: Option<&'h mut dyn Write>,
+    ) -> Compiler<'s, 'h> {
         Compiler {
-            chunk: Chunk::default(),
             scanner: Scanner::new(src),
             previous: None,
             current: None,
-            heap: Heap::new(),
+            heap,
+            frames: vec![FunctionFrame::new(FunctionKind::Script, None, source)],
+            classes: Vec::new(),
+            errors: Vec::new(),
+            repl,
+            code_sink,
+            source,
         }
     }
 
-    pub fn compile(src: &'s str) -> CompileResult<(Chunk, Heap<Object>)> {
-        let mut compiler = Compiler::new(src);
+    /// Compile a script into a function object which takes no arguments,
+    /// allocating its constants in `heap`, and marking every function as from
+    /// `source`. Fails with every error found.
+    /// Unless there are errors, each function's code is written to
+    /// `code_sink` as it's finished.
+    pub fn compile(
+        src: &'s str,
+        source: SourceId,
+        heap: &'h mut Heap,
+        code_sink: Option<&'h mut dyn Write>,
+    ) -> Result<Handle<Object>, Vec<CompileError>> {
+        Compiler::new(src, source, heap, false, code_sink).compile_script()
+    }
 
-        compiler.advance()?;
-        while compiler.current.is_some() {
-            compiler.declaration()?;
+    /// As with `compile`, but a trailing expression statement, whose semicolon
+    /// may be left off, becomes the script's return value.
+    pub fn compile_repl(
+        src: &'s str,
+        source: SourceId,
+        heap: &'h mut Heap,
+        code_sink: Option<&'h mut dyn Write>,
+    ) -> Result<Handle<Object>, Vec<CompileError>> {
+        Compiler::new(src, source, heap, true, code_sink).compile_script()
+    }
+
+    fn compile_script(mut self) -> Result<Handle<Object>, Vec<CompileError>> {
+        self.advance();
+        while self.current.is_some() {
+            self.declaration(true);
         }
-        compiler
-            .chunk
-            .operation(Op::Return, compiler.get_previous()?.start.line);
-        if cfg!(debug_assertions) {
-            compiler.chunk.disassemble("Pre-exec disassembly");
-            println!();
+        let script = self.end_function();
+        if self.errors.is_empty() {
+            Ok(script)
+        } else {
+            Err(self.errors)
         }
-        Ok((compiler.chunk, compiler.heap))
+    }
+
+    #[inline]
+    fn frame(&self) -> &FunctionFrame<'s> {
+        self.frames
+            .last()
+            .expect("The script frame is never popped")
+    }
+
+    #[inline]
+    fn frame_mut(&mut self) -> &mut FunctionFrame<'s> {
+        self.frames
+            .last_mut()
+            .expect("The script frame is never popped")
+    }
+
+    #[inline]
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.frame_mut().function.chunk
+    }
+
+    /// Return nothing: `nil` from most functions, but the instance from an initializer.
+    fn emit_return(&mut self, span: Span) {
+        if self.frame().kind == FunctionKind::Initializer {
+            self.chunk().operation(Op::GetLocal(0), span);
+        } else {
+            self.chunk().operation(Op::Nil, span);
+        }
+        self.chunk().operation(Op::Return, span);
+    }
+
+    /// Finish the innermost function with an implicit empty return, and move
+    /// it to the heap.
+    fn end_function(&mut self) -> Handle<Object> {
+        let span = self.previous.map_or(Span::default(), |t| t.span());
+        self.emit_return(span);
+
+        let frame = self.frames.pop().expect("Unbalanced function frames");
+        if self.errors.is_empty() {
+            if let Some(out) = self.code_sink.as_deref_mut() {
+                // This is only ever for debugging, so it's not worth failing over.
+                let name = frame.function.name.as_deref().unwrap_or("<script>");
+                let _ = frame
+                    .function
+                    .chunk
+                    .disassemble(out, name)
+                    .and_then(|()| writeln!(out));
+            }
+        }
+        self.heap.insert_temp(Object::Function(frame.function))
     }
 
     fn current_precedence(&self) -> Precedence {
@@ -85,38 +420,61 @@ impl<'s> Compiler<'s> {
             .map_or(Precedence::None, |c| Compiler::get_rule(c.typ).precedence)
     }
 
+    fn unexpected_eof(&self) -> CompileError {
+        let span = Span {
+            start: self.scanner.position(),
+            length: 0,
+        };
+        SyntaxError::UnexpectedEOF { span }.into()
+    }
+
     fn get_previous(&self) -> CompileResult<Token> {
-        self.previous.ok_or(SyntaxError::UnexpectedEOF.into())
+        self.previous.ok_or_else(|| self.unexpected_eof())
     }
 
     fn get_current(&self) -> CompileResult<Token> {
-        self.current.ok_or(SyntaxError::UnexpectedEOF.into())
+        self.current.ok_or_else(|| self.unexpected_eof())
     }
 
-    fn advance(&mut self) -> CompileResult<()> {
-        let _ = mem::replace(
-            &mut self.previous,
-            mem::replace(&mut self.current, self.scanner.next_token()?),
-        );
-        Ok(())
+    /// Move on to the next token, collecting any scan errors along the way.
+    fn advance(&mut self) {
+        loop {
+            match self.scanner.next_token() {
+                Ok(next) => {
+                    self.previous = mem::replace(&mut self.current, next);
+                    return;
+                }
+                Err(e) => self.errors.push(e),
+            }
+        }
     }
 
     fn consume(&mut self, expected: TokenType) -> CompileResult<()> {
         let cur = self.get_current()?;
         if cur.typ == expected {
-            self.advance()
+            self.advance();
+            Ok(())
         } else {
             Err(SyntaxError::UnexpectedToken {
                 actual: cur.typ,
                 expected,
-                pos: cur.start,
+                span: cur.span(),
             }
             .into())
         }
     }
 
+    fn match_token(&mut self, expected: TokenType) -> CompileResult<bool> {
+        if self.current.map(|t| t.typ) == Some(expected) {
+            self.advance();
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     #[rustfmt::skip]
-    fn execute(&mut self, instruction: ParseInstruction) -> CompileResult<()> {
+    fn execute(&mut self, instruction: ParseInstruction, can_assign: bool) -> CompileResult<()> {
         match instruction {
             ParseInstruction::Unary    => self.unary(),
             ParseInstruction::Binary   => self.binary(),
@@ -124,24 +482,43 @@ impl<'s> Compiler<'s> {
             ParseInstruction::Number   => self.number(),
             ParseInstruction::Literal  => self.literal(),
             ParseInstruction::String   => self.string(),
+            ParseInstruction::Variable => self.variable(can_assign),
+            ParseInstruction::And      => self.and(),
+            ParseInstruction::Or       => self.or(),
+            ParseInstruction::Call     => self.call(),
+            ParseInstruction::Dot      => self.dot(can_assign),
+            ParseInstruction::This     => self.this(),
+            ParseInstruction::Super    => self.super_(),
         }
     }
 
     fn parse_precedence(&mut self, min: Precedence) -> CompileResult<()> {
-        self.advance()?;
+        self.advance();
 
-        let prefix_instruction = Compiler::get_rule(self.get_previous()?.typ)
+        let token = self.get_previous()?;
+        let prefix_instruction = Compiler::get_rule(token.typ)
             .prefix
-            .ok_or(CompileError::Syntax(SyntaxError::ExpectedPrefix))?;
+            .ok_or(SyntaxError::ExpectedPrefix { span: token.span() })?;
 
-        self.execute(prefix_instruction)?;
+        // Only a prefix parsed at the lowest precedence may be the target of an
+        // assignment; otherwise `a * b = c` would happily assign to `b`.
+        let can_assign = min <= Precedence::Assignment;
+        self.execute(prefix_instruction, can_assign)?;
 
         while min <= self.current_precedence() {
-            self.advance()?;
-            let infix_instruction = Compiler::get_rule(self.get_previous()?.typ)
+            self.advance();
+            let token = self.get_previous()?;
+            let infix_instruction = Compiler::get_rule(token.typ)
                 .infix
-                .ok_or(CompileError::Syntax(SyntaxError::ExpectedInfix))?;
-            self.execute(infix_instruction)?;
+                .ok_or(SyntaxError::ExpectedInfix { span: token.span() })?;
+            self.execute(infix_instruction, can_assign)?;
+        }
+
+        if can_assign && self.match_token(TokenType::Equal)? {
+            return Err(SyntaxError::InvalidAssignmentTarget {
+                span: self.get_previous()?.span(),
+            }
+            .into());
         }
         Ok(())
     }
@@ -150,63 +527,589 @@ impl<'s> Compiler<'s> {
         self.parse_precedence(Precedence::Assignment)
     }
 
-    fn declaration(&mut self) -> CompileResult<()> {
-        let result = self.statement();
-        match result {
-            Ok(()) => Ok(()),
-            Err(e) => {
-                println!("Compile error: {:?}", e);
-                while self.current.is_some() {
-                    if self.get_previous()?.typ == TokenType::Semicolon {
-                        return Ok(());
-                    }
-                    match self.cur_typ()? {
-                        TokenType::Class
-                        | TokenType::Fun
-                        | TokenType::Var
-                        | TokenType::For
-                        | TokenType::If
-                        | TokenType::While
-                        | TokenType::Print
-                        | TokenType::Return => return Ok(()),
-                        _ => {}
-                    }
-                    self.advance()?;
-                }
-                Ok(())
+    /// Compile a declaration, recording any error and skipping ahead to the
+    /// next one, so that a single pass reports as many errors as possible.
+    /// `top_level` is only set for the script's own declarations, not those
+    /// nested in blocks or functions.
+    fn declaration(&mut self, top_level: bool) {
+        let frames = self.frames.len();
+        let classes = self.classes.len();
+        let scope_depth = self.frame().scope_depth;
+        let locals = self.frame().locals.len();
+
+        if let Err(e) = self.declaration_inner(top_level) {
+            self.errors.push(e);
+            // Abandon anything the failed declaration had opened. Nothing
+            // compiled from here on will be run, but it should still be parsed
+            // in the right context.
+            self.frames.truncate(frames);
+            self.classes.truncate(classes);
+            let frame = self.frame_mut();
+            frame.scope_depth = scope_depth;
+            frame.locals.truncate(locals);
+            self.synchronize();
+        }
+    }
+
+    fn declaration_inner(&mut self, top_level: bool) -> CompileResult<()> {
+        match self.cur_typ()? {
+            TokenType::Var => {
+                self.advance();
+                self.var_declaration()
+            }
+            TokenType::Fun => {
+                self.advance();
+                self.fun_declaration()
             }
+            TokenType::Class => {
+                self.advance();
+                self.class_declaration()
+            }
+            _ => self.statement(top_level),
+        }
+    }
+
+    /// Skip tokens until the end of a statement or the start of the next.
+    fn synchronize(&mut self) {
+        while let Some(current) = self.current {
+            if self.previous.map(|t| t.typ) == Some(TokenType::Semicolon) {
+                return;
+            }
+            match current.typ {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => {}
+            }
+            self.advance();
         }
     }
 
     fn cur_typ(&self) -> CompileResult<TokenType> {
         self.current
             .map(|t| t.typ)
-            .ok_or(SyntaxError::UnexpectedEOF.into())
+            .ok_or_else(|| self.unexpected_eof())
+    }
+
+    fn lexeme(&self, token: Token) -> &'s str {
+        &self.scanner.src[token.start.pos..][..token.length]
+    }
+
+    fn identifier_constant(&mut self, token: Token) -> u24 {
+        let name = Object::Str(self.scanner.substr(token.start, token.length));
+        let handle = self.heap.insert_temp(name);
+        u24::from(self.chunk().add_constant(Value::Obj(handle)))
+    }
+
+    /// Add a local to the current scope. `name` is usually a lexeme, but may be
+    /// synthetic, e.g. `super`.
+    fn add_local(&mut self, name: &'s str, span: Span) -> CompileResult<()> {
+        if self.frame().locals.len() == MAX_LOCALS {
+            return Err(SyntaxError::TooManyLocals { span }.into());
+        }
+        self.frame_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+        Ok(())
+    }
+
+    fn declare_variable(&mut self, name: Token) -> CompileResult<()> {
+        if self.frame().scope_depth == 0 {
+            return Ok(());
+        }
+
+        let lexeme = self.lexeme(name);
+        let scope_depth = self.frame().scope_depth;
+        let redeclared = self
+            .frame()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == lexeme);
+        if redeclared {
+            return Err(SyntaxError::DuplicateDeclaration {
+                span: name.span(),
+                name: lexeme.to_string(),
+            }
+            .into());
+        }
+
+        self.add_local(lexeme, name.span())
+    }
+
+    /// Consume a variable name, declaring it in the current scope. Yields the
+    /// name's constant index for globals; locals live on the stack and need none.
+    fn parse_variable(&mut self) -> CompileResult<u24> {
+        self.consume(TokenType::Identifier)?;
+        let name = self.get_previous()?;
+
+        self.declare_variable(name)?;
+        if self.frame().scope_depth > 0 {
+            return Ok(u24::from(0));
+        }
+        Ok(self.identifier_constant(name))
+    }
+
+    /// Make the most recently declared local available for use.
+    fn mark_initialized(&mut self) {
+        let frame = self.frame_mut();
+        if frame.scope_depth == 0 {
+            return;
+        }
+        let scope_depth = frame.scope_depth;
+        if let Some(local) = frame.locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: u24, span: Span) {
+        if self.frame().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.chunk().operation(Op::DefineGlobal(global), span);
+    }
+
+    /// Find the stack slot of a local in the function compiled by `self.frames[frame]`.
+    fn resolve_local(&self, frame: usize, name: &str, span: Span) -> CompileResult<Option<u8>> {
+        let locals = &self.frames[frame].locals;
+        match locals.iter().rposition(|local| local.name == name) {
+            None => Ok(None),
+            Some(slot) => match locals[slot].depth {
+                None => Err(SyntaxError::SelfReferentialInitializer {
+                    span,
+                    name: name.to_string(),
+                }
+                .into()),
+                // `add_local` guarantees that this fits.
+                Some(_) => Ok(Some(slot as u8)),
+            },
+        }
+    }
+
+    /// Find a variable from an enclosing function, threading it through every
+    /// function between there and `self.frames[frame]` as an upvalue.
+    fn resolve_upvalue(
+        &mut self,
+        frame: usize,
+        name: &str,
+        span: Span,
+    ) -> CompileResult<Option<u8>> {
+        if frame == 0 {
+            return Ok(None);
+        }
+
+        let enclosing = frame - 1;
+        if let Some(slot) = self.resolve_local(enclosing, name, span)? {
+            self.frames[enclosing].locals[usize::from(slot)].is_captured = true;
+            let upvalue = UpvalueRef {
+                is_local: true,
+                index: slot,
+            };
+            return self.add_upvalue(frame, upvalue, span).map(Some);
+        }
+
+        if let Some(index) = self.resolve_upvalue(enclosing, name, span)? {
+            let upvalue = UpvalueRef {
+                is_local: false,
+                index,
+            };
+            return self.add_upvalue(frame, upvalue, span).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, frame: usize, upvalue: UpvalueRef, span: Span) -> CompileResult<u8> {
+        let upvalues = &mut self.frames[frame].function.upvalues;
+        // `MAX_UPVALUES` guarantees that these fit.
+        if let Some(index) = upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(index as u8);
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            return Err(SyntaxError::TooManyUpvalues { span }.into());
+        }
+        upvalues.push(upvalue);
+        Ok((upvalues.len() - 1) as u8)
+    }
+
+    fn var_declaration(&mut self) -> CompileResult<()> {
+        let global = self.parse_variable()?;
+        let span = self.get_previous()?.span();
+
+        if self.match_token(TokenType::Equal)? {
+            self.expression()?;
+        } else {
+            self.chunk().operation(Op::Nil, span);
+        }
+        self.consume(TokenType::Semicolon)?;
+
+        self.define_variable(global, span);
+        Ok(())
+    }
+
+    fn class_declaration(&mut self) -> CompileResult<()> {
+        self.consume(TokenType::Identifier)?;
+        let name = self.get_previous()?;
+        let name_constant = self.identifier_constant(name);
+        self.declare_variable(name)?;
+
+        self.chunk()
+            .operation(Op::Class(name_constant), name.span());
+        self.define_variable(name_constant, name.span());
+
+        self.classes.push(ClassFrame {
+            has_superclass: false,
+        });
+        let result = self.superclass(name).and_then(|()| self.class_body(name));
+        let class = self.classes.pop().expect("Unbalanced class frames");
+        if class.has_superclass {
+            self.end_scope(self.get_previous()?.span());
+        }
+        result
+    }
+
+    /// Compile an optional `< Superclass` clause. The superclass is kept in a
+    /// local named `super` for the class's methods to capture.
+    fn superclass(&mut self, name: Token) -> CompileResult<()> {
+        if !self.match_token(TokenType::Less)? {
+            return Ok(());
+        }
+        self.consume(TokenType::Identifier)?;
+        let superclass = self.get_previous()?;
+        self.variable(false)?;
+        if self.lexeme(superclass) == self.lexeme(name) {
+            return Err(SyntaxError::InheritFromSelf {
+                span: superclass.span(),
+                name: self.lexeme(superclass).to_string(),
+            }
+            .into());
+        }
+
+        self.begin_scope();
+        self.add_local("super", superclass.span())?;
+        self.mark_initialized();
+        if let Some(class) = self.classes.last_mut() {
+            class.has_superclass = true;
+        }
+
+        self.named_variable(self.lexeme(name), name.span(), false)?;
+        // Attributed to the superclass, which is what must turn out to be a class.
+        self.chunk().operation(Op::Inherit, superclass.span());
+        Ok(())
+    }
+
+    fn class_body(&mut self, name: Token) -> CompileResult<()> {
+        // Keep the class on the stack while its methods are attached.
+        self.named_variable(self.lexeme(name), name.span(), false)?;
+        self.consume(TokenType::LeftBrace)?;
+        while self.current.is_some_and(|t| t.typ != TokenType::RightBrace) {
+            self.method()?;
+        }
+        self.consume(TokenType::RightBrace)?;
+        let span = self.get_previous()?.span();
+        self.chunk().operation(Op::Pop, span);
+        Ok(())
+    }
+
+    fn method(&mut self) -> CompileResult<()> {
+        self.consume(TokenType::Identifier)?;
+        let name = self.get_previous()?;
+        let name_constant = self.identifier_constant(name);
+        let kind = if self.lexeme(name) == Class::INITIALIZER {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(kind)?;
+        self.chunk()
+            .operation(Op::Method(name_constant), name.span());
+        Ok(())
+    }
+
+    fn fun_declaration(&mut self) -> CompileResult<()> {
+        let global = self.parse_variable()?;
+        let span = self.get_previous()?.span();
+        // Unlike variables, functions may refer to themselves in their bodies.
+        self.mark_initialized();
+        self.function(FunctionKind::Function)?;
+        self.define_variable(global, span);
+        Ok(())
+    }
+
+    /// Compile a parameter list and body into a new function, and emit it as a
+    /// constant in the enclosing function.
+    fn function(&mut self, kind: FunctionKind) -> CompileResult<()> {
+        let name = self.get_previous()?;
+        self.frames.push(FunctionFrame::new(
+            kind,
+            Some(self.lexeme(name).to_string()),
+            self.source,
+        ));
+        let result = self.function_body();
+        let function = self.end_function();
+        result?;
+
+        let span = self.get_previous()?.span();
+        let index = self.chunk().add_constant(Value::Obj(function));
+        self.chunk().operation(Op::Closure(u24::from(index)), span);
+        Ok(())
+    }
+
+    fn function_body(&mut self) -> CompileResult<()> {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen)?;
+        if !self.match_token(TokenType::RightParen)? {
+            loop {
+                let param = self.get_current()?;
+                if self.frame().function.arity == u8::MAX {
+                    return Err(SyntaxError::TooManyParameters { span: param.span() }.into());
+                }
+                self.frame_mut().function.arity += 1;
+                let constant = self.parse_variable()?;
+                self.define_variable(constant, param.span());
+                if !self.match_token(TokenType::Comma)? {
+                    break;
+                }
+            }
+            self.consume(TokenType::RightParen)?;
+        }
+        self.consume(TokenType::LeftBrace)?;
+        self.block()
+    }
+
+    fn begin_scope(&mut self) {
+        self.frame_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self, span: Span) {
+        self.frame_mut().scope_depth -= 1;
+
+        let scope_depth = self.frame().scope_depth;
+        while self
+            .frame()
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_none_or(|d| d > scope_depth))
+        {
+            let captured = self.frame().locals.last().is_some_and(|l| l.is_captured);
+            let op = if captured { Op::CloseUpvalue } else { Op::Pop };
+            self.chunk().operation(op, span);
+            self.frame_mut().locals.pop();
+        }
+    }
+
+    fn block(&mut self) -> CompileResult<()> {
+        while self.current.is_some_and(|t| t.typ != TokenType::RightBrace) {
+            self.declaration(false);
+        }
+        self.consume(TokenType::RightBrace)
+    }
+
+    /// Emit a jump with a placeholder offset, returning its position for
+    /// `patch_jump`.
+    fn emit_jump(&mut self, op: Op, span: Span) -> usize {
+        let op_pos = self.chunk().code_len();
+        self.chunk().operation(op, span);
+        op_pos
+    }
+
+    /// Point the jump at `op_pos` to the next instruction to be written.
+    /// `span` is blamed if the distance doesn't fit in the jump's operand.
+    fn patch_jump(&mut self, op_pos: usize, span: Span) -> CompileResult<()> {
+        let distance = self.chunk().code_len() - op_pos - JUMP_COST;
+        let offset = u16::try_from(distance).map_err(|_| SyntaxError::JumpTooLarge { span })?;
+        self.chunk().patch_jump(op_pos, offset);
+        Ok(())
+    }
+
+    /// Emit a backward jump to `loop_start`.
+    fn emit_loop(&mut self, loop_start: usize, span: Span) -> CompileResult<()> {
+        let distance = self.chunk().code_len() - loop_start + JUMP_COST;
+        let offset = u16::try_from(distance).map_err(|_| SyntaxError::JumpTooLarge { span })?;
+        self.chunk().operation(Op::Loop(offset), span);
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> CompileResult<()> {
+        let token = self.get_previous()?;
+        let span = token.span();
+        self.consume(TokenType::LeftParen)?;
+        self.expression()?;
+        self.consume(TokenType::RightParen)?;
+
+        let then_jump = self.emit_jump(Op::JumpIfFalse(u16::MAX), span);
+        self.chunk().operation(Op::Pop, span);
+        self.statement(false)?;
+
+        let else_jump = self.emit_jump(Op::Jump(u16::MAX), span);
+        self.patch_jump(then_jump, token.span())?;
+        self.chunk().operation(Op::Pop, span);
+
+        if self.match_token(TokenType::Else)? {
+            self.statement(false)?;
+        }
+        self.patch_jump(else_jump, token.span())
+    }
+
+    fn while_statement(&mut self) -> CompileResult<()> {
+        let token = self.get_previous()?;
+        let span = token.span();
+        let loop_start = self.chunk().code_len();
+        self.consume(TokenType::LeftParen)?;
+        self.expression()?;
+        self.consume(TokenType::RightParen)?;
+
+        let exit_jump = self.emit_jump(Op::JumpIfFalse(u16::MAX), span);
+        self.chunk().operation(Op::Pop, span);
+        self.statement(false)?;
+        self.emit_loop(loop_start, token.span())?;
+
+        self.patch_jump(exit_jump, token.span())?;
+        self.chunk().operation(Op::Pop, span);
+        Ok(())
+    }
+
+    fn for_statement(&mut self) -> CompileResult<()> {
+        self.begin_scope();
+        let result = self.for_clauses_and_body();
+        self.end_scope(self.get_previous()?.span());
+        result
+    }
+
+    /// `for (init; cond; incr) body` is compiled as `{ init; while (cond) { body; incr; } }`,
+    /// with the increment emitted before the body and jumped around on entry.
+    fn for_clauses_and_body(&mut self) -> CompileResult<()> {
+        let token = self.get_previous()?;
+        let span = token.span();
+        self.consume(TokenType::LeftParen)?;
+
+        match self.cur_typ()? {
+            TokenType::Semicolon => self.advance(),
+            TokenType::Var => {
+                self.advance();
+                self.var_declaration()?;
+            }
+            _ => self.expression_statement(false)?,
+        }
+
+        let mut loop_start = self.chunk().code_len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon)? {
+            self.expression()?;
+            self.consume(TokenType::Semicolon)?;
+
+            exit_jump = Some(self.emit_jump(Op::JumpIfFalse(u16::MAX), span));
+            self.chunk().operation(Op::Pop, span);
+        }
+
+        if !self.match_token(TokenType::RightParen)? {
+            let body_jump = self.emit_jump(Op::Jump(u16::MAX), span);
+            let increment_start = self.chunk().code_len();
+            self.expression()?;
+            self.chunk().operation(Op::Pop, span);
+            self.consume(TokenType::RightParen)?;
+
+            self.emit_loop(loop_start, token.span())?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump, token.span())?;
+        }
+
+        self.statement(false)?;
+        self.emit_loop(loop_start, token.span())?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump, token.span())?;
+            self.chunk().operation(Op::Pop, span);
+        }
+        Ok(())
+    }
+
+    fn return_statement(&mut self) -> CompileResult<()> {
+        let token = self.get_previous()?;
+        if self.frame().kind == FunctionKind::Script {
+            return Err(SyntaxError::ReturnFromTopLevel { span: token.span() }.into());
+        }
+
+        if self.match_token(TokenType::Semicolon)? {
+            self.emit_return(token.span());
+            return Ok(());
+        }
+        if self.frame().kind == FunctionKind::Initializer {
+            return Err(SyntaxError::ReturnValueFromInitializer { span: token.span() }.into());
+        }
+        self.expression()?;
+        self.consume(TokenType::Semicolon)?;
+        self.chunk().operation(Op::Return, token.span());
+        Ok(())
     }
 
     fn print_statement(&mut self) -> CompileResult<()> {
-        let pos = self.get_previous()?.start;
+        let span = self.get_previous()?.span();
         self.expression()?;
         self.consume(TokenType::Semicolon)?;
-        self.chunk.operation(Op::Print, pos.line);
+        self.chunk().operation(Op::Print, span);
         Ok(())
     }
 
-    fn expression_statement(&mut self) -> CompileResult<()> {
-        let pos = self.get_current()?.start;
+    fn expression_statement(&mut self, top_level: bool) -> CompileResult<()> {
+        let span = self.get_current()?.span();
         self.expression()?;
+        let trailing = self.repl && top_level;
+        if trailing && self.current.is_none() {
+            self.chunk().operation(Op::Return, span);
+            return Ok(());
+        }
         self.consume(TokenType::Semicolon)?;
-        self.chunk.operation(Op::Pop, pos.line);
+        if trailing && self.current.is_none() {
+            self.chunk().operation(Op::Return, span);
+        } else {
+            self.chunk().operation(Op::Pop, span);
+        }
         Ok(())
     }
 
-    fn statement(&mut self) -> CompileResult<()> {
+    /// Compile a statement. Only a `top_level` expression statement can be
+    /// the REPL's trailing expression; the bodies of loops and branches never
+    /// are, even unbraced.
+    fn statement(&mut self, top_level: bool) -> CompileResult<()> {
         match self.cur_typ()? {
             TokenType::Print => {
-                self.advance()?;
+                self.advance();
                 self.print_statement()
             }
-            _ => self.expression_statement(),
+            TokenType::If => {
+                self.advance();
+                self.if_statement()
+            }
+            TokenType::Return => {
+                self.advance();
+                self.return_statement()
+            }
+            TokenType::While => {
+                self.advance();
+                self.while_statement()
+            }
+            TokenType::For => {
+                self.advance();
+                self.for_statement()
+            }
+            TokenType::LeftBrace => {
+                self.advance();
+                self.begin_scope();
+                let result = self.block();
+                self.end_scope(self.get_previous()?.span());
+                result
+            }
+            _ => self.expression_statement(top_level),
         }
     }
 
@@ -219,7 +1122,7 @@ impl<'s> Compiler<'s> {
                 let val = s.parse().map_err(|err| {
                     CompileError::Internal(format!("Failed to parse number. Cause: {}", err))
                 })?;
-                self.chunk.push_const(Value::Double(val), prev.start.line);
+                self.chunk().push_const(Value::Double(val), prev.span());
                 Ok(())
             }
             _ => Err(CompileError::Internal(format!(
@@ -240,11 +1143,11 @@ impl<'s> Compiler<'s> {
 
         match operator.typ {
             TokenType::Minus => {
-                self.chunk.operation(Op::Negate, operator.start.line);
+                self.chunk().operation(Op::Negate, operator.span());
                 Ok(())
             }
             TokenType::Bang => {
-                self.chunk.operation(Op::Not, operator.start.line);
+                self.chunk().operation(Op::Not, operator.span());
                 Ok(())
             }
             _ => Err(CompileError::Internal(format!(
@@ -262,46 +1165,46 @@ impl<'s> Compiler<'s> {
 
         match token.typ {
             TokenType::Plus => {
-                self.chunk.operation(Op::Add, token.start.line);
+                self.chunk().operation(Op::Add, token.span());
                 Ok(())
             }
             TokenType::Minus => {
-                self.chunk.operation(Op::Subtract, token.start.line);
+                self.chunk().operation(Op::Subtract, token.span());
                 Ok(())
             }
             TokenType::Star => {
-                self.chunk.operation(Op::Multiply, token.start.line);
+                self.chunk().operation(Op::Multiply, token.span());
                 Ok(())
             }
             TokenType::Slash => {
-                self.chunk.operation(Op::Divide, token.start.line);
+                self.chunk().operation(Op::Divide, token.span());
                 Ok(())
             }
             TokenType::EqualEqual => {
-                self.chunk.operation(Op::Equal, token.start.line);
+                self.chunk().operation(Op::Equal, token.span());
                 Ok(())
             }
             TokenType::Greater => {
-                self.chunk.operation(Op::Greater, token.start.line);
+                self.chunk().operation(Op::Greater, token.span());
                 Ok(())
             }
             TokenType::Less => {
-                self.chunk.operation(Op::Less, token.start.line);
+                self.chunk().operation(Op::Less, token.span());
                 Ok(())
             }
             TokenType::BangEqual => {
-                self.chunk.operation(Op::Equal, token.start.line);
-                self.chunk.operation(Op::Not, token.start.line);
+                self.chunk().operation(Op::Equal, token.span());
+                self.chunk().operation(Op::Not, token.span());
                 Ok(())
             }
             TokenType::GreaterEqual => {
-                self.chunk.operation(Op::Less, token.start.line);
-                self.chunk.operation(Op::Not, token.start.line);
+                self.chunk().operation(Op::Less, token.span());
+                self.chunk().operation(Op::Not, token.span());
                 Ok(())
             }
             TokenType::LessEqual => {
-                self.chunk.operation(Op::Greater, token.start.line);
-                self.chunk.operation(Op::Not, token.start.line);
+                self.chunk().operation(Op::Greater, token.span());
+                self.chunk().operation(Op::Not, token.span());
                 Ok(())
             }
             _ => Err(CompileError::Internal(format!(
@@ -311,19 +1214,126 @@ impl<'s> Compiler<'s> {
         }
     }
 
+    /// The left operand is left on the stack as the result if it is falsey;
+    /// otherwise it is discarded in favour of the right operand.
+    fn and(&mut self) -> CompileResult<()> {
+        let token = self.get_previous()?;
+        let end_jump = self.emit_jump(Op::JumpIfFalse(u16::MAX), token.span());
+        self.chunk().operation(Op::Pop, token.span());
+        self.parse_precedence(Precedence::And)?;
+        self.patch_jump(end_jump, token.span())
+    }
+
+    /// The left operand is left on the stack as the result if it is truthy;
+    /// otherwise it is discarded in favour of the right operand.
+    fn or(&mut self) -> CompileResult<()> {
+        let token = self.get_previous()?;
+        let else_jump = self.emit_jump(Op::JumpIfFalse(u16::MAX), token.span());
+        let end_jump = self.emit_jump(Op::Jump(u16::MAX), token.span());
+
+        self.patch_jump(else_jump, token.span())?;
+        self.chunk().operation(Op::Pop, token.span());
+        self.parse_precedence(Precedence::Or)?;
+        self.patch_jump(end_jump, token.span())
+    }
+
+    fn argument_list(&mut self) -> CompileResult<u8> {
+        let mut arg_count: u8 = 0;
+        if !self.match_token(TokenType::RightParen)? {
+            loop {
+                let arg = self.get_current()?;
+                self.expression()?;
+                arg_count = arg_count
+                    .checked_add(1)
+                    .ok_or(SyntaxError::TooManyArguments { span: arg.span() })?;
+                if !self.match_token(TokenType::Comma)? {
+                    break;
+                }
+            }
+            self.consume(TokenType::RightParen)?;
+        }
+        Ok(arg_count)
+    }
+
+    fn call(&mut self) -> CompileResult<()> {
+        let token = self.get_previous()?;
+        let arg_count = self.argument_list()?;
+        self.chunk().operation(Op::Call(arg_count), token.span());
+        Ok(())
+    }
+
+    fn dot(&mut self, can_assign: bool) -> CompileResult<()> {
+        self.consume(TokenType::Identifier)?;
+        let name = self.get_previous()?;
+        let name_constant = self.identifier_constant(name);
+
+        if can_assign && self.match_token(TokenType::Equal)? {
+            self.expression()?;
+            self.chunk()
+                .operation(Op::SetProperty(name_constant), name.span());
+        } else if self.match_token(TokenType::LeftParen)? {
+            // Calling a method straight away doesn't need a bound method.
+            let arg_count = self.argument_list()?;
+            self.chunk()
+                .operation(Op::Invoke(name_constant, arg_count), name.span());
+        } else {
+            self.chunk()
+                .operation(Op::GetProperty(name_constant), name.span());
+        }
+        Ok(())
+    }
+
+    fn this(&mut self) -> CompileResult<()> {
+        let token = self.get_previous()?;
+        if self.classes.is_empty() {
+            return Err(SyntaxError::ThisOutsideClass { span: token.span() }.into());
+        }
+        // `this` can't be assigned to.
+        self.variable(false)
+    }
+
+    fn super_(&mut self) -> CompileResult<()> {
+        let token = self.get_previous()?;
+        match self.classes.last() {
+            None => return Err(SyntaxError::SuperOutsideClass { span: token.span() }.into()),
+            Some(class) if !class.has_superclass => {
+                return Err(SyntaxError::SuperWithoutSuperclass { span: token.span() }.into())
+            }
+            Some(_) => {}
+        }
+
+        self.consume(TokenType::Dot)?;
+        self.consume(TokenType::Identifier)?;
+        let name = self.get_previous()?;
+        let name_constant = self.identifier_constant(name);
+
+        self.named_variable("this", token.span(), false)?;
+        if self.match_token(TokenType::LeftParen)? {
+            let arg_count = self.argument_list()?;
+            self.named_variable("super", token.span(), false)?;
+            self.chunk()
+                .operation(Op::SuperInvoke(name_constant, arg_count), name.span());
+        } else {
+            self.named_variable("super", token.span(), false)?;
+            self.chunk()
+                .operation(Op::GetSuper(name_constant), name.span());
+        }
+        Ok(())
+    }
+
     fn literal(&mut self) -> CompileResult<()> {
         let token = self.get_previous()?;
         match token.typ {
             TokenType::True => {
-                self.chunk.operation(Op::True, token.start.line);
+                self.chunk().operation(Op::True, token.span());
                 Ok(())
             }
             TokenType::False => {
-                self.chunk.operation(Op::False, token.start.line);
+                self.chunk().operation(Op::False, token.span());
                 Ok(())
             }
             TokenType::Nil => {
-                self.chunk.operation(Op::Nil, token.start.line);
+                self.chunk().operation(Op::Nil, token.span());
                 Ok(())
             }
             _ => Err(CompileError::Internal(format!(
@@ -339,7 +1349,7 @@ impl<'s> Compiler<'s> {
             TokenType::String => {
                 let obj = Object::Str(self.scanner.src[token.start.pos..][..token.length].into());
                 let handle = self.heap.insert_temp(obj);
-                self.chunk.push_const(Value::Obj(handle), token.start.line);
+                self.chunk().push_const(Value::Obj(handle), token.span());
                 Ok(())
             }
             _ => Err(CompileError::Internal(format!(
@@ -349,15 +1359,41 @@ impl<'s> Compiler<'s> {
         }
     }
 
+    fn variable(&mut self, can_assign: bool) -> CompileResult<()> {
+        let name = self.get_previous()?;
+        self.named_variable(self.lexeme(name), name.span(), can_assign)
+    }
+
+    fn named_variable(&mut self, name: &str, span: Span, can_assign: bool) -> CompileResult<()> {
+        let frame = self.frames.len() - 1;
+        let (get_op, set_op) = if let Some(slot) = self.resolve_local(frame, name, span)? {
+            (Op::GetLocal(slot), Op::SetLocal(slot))
+        } else if let Some(index) = self.resolve_upvalue(frame, name, span)? {
+            (Op::GetUpvalue(index), Op::SetUpvalue(index))
+        } else {
+            let handle = self.heap.insert_temp(Object::Str(name.to_string()));
+            let global = u24::from(self.chunk().add_constant(Value::Obj(handle)));
+            (Op::GetGlobal(global), Op::SetGlobal(global))
+        };
+
+        if can_assign && self.match_token(TokenType::Equal)? {
+            self.expression()?;
+            self.chunk().operation(set_op, span);
+        } else {
+            self.chunk().operation(get_op, span);
+        }
+        Ok(())
+    }
+
     #[rustfmt::skip]
     fn get_rule(value: TokenType) -> ParseRule {
         match value {
-            TokenType::LeftParen =>    ParseRule { prefix: Some(ParseInstruction::Grouping), infix: None,                           precedence: Precedence::None,       },
+            TokenType::LeftParen =>    ParseRule { prefix: Some(ParseInstruction::Grouping), infix: Some(ParseInstruction::Call),   precedence: Precedence::Call,       },
             TokenType::RightParen =>   ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
             TokenType::LeftBrace =>    ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
             TokenType::RightBrace =>   ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
             TokenType::Comma =>        ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
-            TokenType::Dot =>          ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
+            TokenType::Dot =>          ParseRule { prefix: None,                             infix: Some(ParseInstruction::Dot),    precedence: Precedence::Call,       },
             TokenType::Minus =>        ParseRule { prefix: Some(ParseInstruction::Unary),    infix: Some(ParseInstruction::Binary), precedence: Precedence::Term,       },
             TokenType::Plus =>         ParseRule { prefix: None,                             infix: Some(ParseInstruction::Binary), precedence: Precedence::Term,       },
             TokenType::Semicolon =>    ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
@@ -371,10 +1407,10 @@ impl<'s> Compiler<'s> {
             TokenType::GreaterEqual => ParseRule { prefix: None,                             infix: Some(ParseInstruction::Binary), precedence: Precedence::Comparison, },
             TokenType::Less =>         ParseRule { prefix: None,                             infix: Some(ParseInstruction::Binary), precedence: Precedence::Comparison, },
             TokenType::LessEqual =>    ParseRule { prefix: None,                             infix: Some(ParseInstruction::Binary), precedence: Precedence::Comparison, },
-            TokenType::Identifier =>   ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
+            TokenType::Identifier =>   ParseRule { prefix: Some(ParseInstruction::Variable), infix: None,                           precedence: Precedence::None,       },
             TokenType::String =>       ParseRule { prefix: Some(ParseInstruction::String),   infix: None,                           precedence: Precedence::None,       },
             TokenType::Number =>       ParseRule { prefix: Some(ParseInstruction::Number),   infix: None,                           precedence: Precedence::None,       },
-            TokenType::And =>          ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
+            TokenType::And =>          ParseRule { prefix: None,                             infix: Some(ParseInstruction::And),    precedence: Precedence::And,        },
             TokenType::Class =>        ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
             TokenType::Else =>         ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
             TokenType::False =>        ParseRule { prefix: Some(ParseInstruction::Literal),  infix: None,                           precedence: Precedence::None,       },
@@ -382,11 +1418,11 @@ impl<'s> Compiler<'s> {
             TokenType::Fun =>          ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
             TokenType::If =>           ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
             TokenType::Nil =>          ParseRule { prefix: Some(ParseInstruction::Literal),  infix: None,                           precedence: Precedence::None,       },
-            TokenType::Or =>           ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
+            TokenType::Or =>           ParseRule { prefix: None,                             infix: Some(ParseInstruction::Or),     precedence: Precedence::Or,         },
             TokenType::Print =>        ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
             TokenType::Return =>       ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
-            TokenType::Super =>        ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
-            TokenType::This =>         ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
+            TokenType::Super =>        ParseRule { prefix: Some(ParseInstruction::Super),    infix: None,                           precedence: Precedence::None,       },
+            TokenType::This =>         ParseRule { prefix: Some(ParseInstruction::This),     infix: None,                           precedence: Precedence::None,       },
             TokenType::True =>         ParseRule { prefix: Some(ParseInstruction::Literal),  infix: None,                           precedence: Precedence::None,       },
             TokenType::Var =>          ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
             TokenType::While =>        ParseRule { prefix: None,                             infix: None,                           precedence: Precedence::None,       },
@@ -418,6 +1454,13 @@ enum ParseInstruction {
     Number,
     Literal,
     String,
+    Variable,
+    And,
+    Or,
+    Call,
+    Dot,
+    This,
+    Super,
 }
 
 struct ParseRule {
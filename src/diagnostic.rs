@@ -0,0 +1,387 @@
+use std::io::{stderr, IsTerminal};
+
+use crate::compiler::CompileError;
+use crate::scanner::Span;
+use crate::vm::InterpretError;
+
+const RED: &str = "\x1b[31m";
+const BLUE: &str = "\x1b[34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// How diagnostics are written out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    /// For people: source snippets with caret underlines.
+    Human,
+    /// For tools: one JSON object per line. Columns and lengths count
+    /// characters, not bytes.
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn parse(name: &str) -> Option<ErrorFormat> {
+        match name {
+            "human" => Some(ErrorFormat::Human),
+            "json" => Some(ErrorFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// An error, ready to be shown against the source it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Identifies the kind of error for tools to match on, and is stable
+    /// across releases, unlike the message. Codes are grouped by where the
+    /// error is found:
+    ///
+    /// - `E00xx`: scanning
+    /// - `E01xx`: parsing and compiling
+    /// - `E02xx`: running
+    /// - `E09xx`: bugs in the interpreter itself
+    ///
+    /// A code is never reused for a different error, even once the error it
+    /// was given to is gone. That's why there's no `E0202`, which was for
+    /// values that couldn't be used as booleans.
+    pub code: &'static str,
+    pub message: String,
+    /// `None` for errors that can't be blamed on any particular source.
+    pub span: Option<Span>,
+    /// Shown after the source snippet, one per line.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Every diagnostic an interpreter error amounts to, in source order.
+    pub fn from_interpret_error(error: &InterpretError) -> Vec<Diagnostic> {
+        match error {
+            InterpretError::Compile(errors) => errors.iter().map(Diagnostic::from).collect(),
+            InterpretError::Runtime {
+                error, span, trace, ..
+            } => vec![Diagnostic {
+                severity: Severity::Error,
+                code: error.code(),
+                message: error.to_string(),
+                span: Some(*span),
+                notes: trace.iter().map(ToString::to_string).collect(),
+            }],
+        }
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(error: &CompileError) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: error.code(),
+            message: error.to_string(),
+            span: error.span(),
+            notes: Vec::new(),
+        }
+    }
+}
+
+/// The one-based line and character column a span starts at, its length in
+/// characters, how many characters of it are on its first line, and the bounds
+/// of that line in the source.
+struct Location {
+    line: usize,
+    column: usize,
+    length: usize,
+    underlined: usize,
+    line_start: usize,
+    line_end: usize,
+}
+
+/// Renders diagnostics in a given `ErrorFormat`. For people, that's the file
+/// and position, the offending source line and a caret underline.
+pub struct Renderer<'a> {
+    file: &'a str,
+    src: &'a str,
+    format: ErrorFormat,
+    colour: bool,
+}
+
+impl<'a> Renderer<'a> {
+    /// Colour is used if stderr, where diagnostics are written, is a terminal.
+    pub fn new(file: &'a str, src: &'a str) -> Renderer<'a> {
+        Renderer {
+            file,
+            src,
+            format: ErrorFormat::Human,
+            colour: stderr().is_terminal(),
+        }
+    }
+
+    pub fn with_format(self, format: ErrorFormat) -> Renderer<'a> {
+        Renderer { format, ..self }
+    }
+
+    #[cfg(test)]
+    pub fn with_colour(self, colour: bool) -> Renderer<'a> {
+        Renderer { colour, ..self }
+    }
+
+    fn paint(&self, colour: &str, text: &str) -> String {
+        if self.colour {
+            format!("{}{}{}", colour, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// Render a diagnostic, including its trailing newline.
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        match self.format {
+            ErrorFormat::Human => self.render_human(diagnostic),
+            ErrorFormat::Json => self.render_json(diagnostic),
+        }
+    }
+
+    fn render_human(&self, diagnostic: &Diagnostic) -> String {
+        let mut out = format!(
+            "{}{}\n",
+            self.paint(&format!("{}{}", BOLD, RED), diagnostic.severity.name()),
+            self.paint(BOLD, &format!(": {}", diagnostic.message))
+        );
+
+        // Spans which can't be found in the source just name the file.
+        match diagnostic.span.and_then(|span| self.locate(span)) {
+            None => out.push_str(&format!("{} {}\n", self.paint(BLUE, "-->"), self.file)),
+            Some(location) => out.push_str(&self.snippet(location)),
+        }
+        for note in &diagnostic.notes {
+            out.push_str(&format!("  {}\n", note));
+        }
+        out
+    }
+
+    /// Find `span` in the source, or `None` if it can't have come from it:
+    /// it's out of bounds, starts mid-character, or isn't on the line it
+    /// claims to be.
+    fn locate(&self, span: Span) -> Option<Location> {
+        let pos = span.start.pos;
+        let line_start = pos.checked_sub(span.start.column)?;
+        let before = self.src.get(..line_start)?;
+        if !(before.is_empty() || before.ends_with('\n'))
+            || before.matches('\n').count() != span.start.line
+        {
+            return None;
+        }
+        let line_end = self.src[line_start..]
+            .find('\n')
+            .map_or(self.src.len(), |i| line_start + i);
+        let end = (pos + span.length).min(self.src.len());
+        // Lines and columns count from zero internally, and columns and
+        // lengths in bytes.
+        Some(Location {
+            line: span.start.line + 1,
+            column: self.src.get(line_start..pos)?.chars().count() + 1,
+            length: self.src.get(pos..end)?.chars().count(),
+            underlined: self.src.get(pos..end.min(line_end))?.chars().count().max(1),
+            line_start,
+            line_end,
+        })
+    }
+
+    /// The location line, source line and underline for a located span.
+    /// Spans running over several lines are only underlined to the end of
+    /// the first.
+    fn snippet(&self, location: Location) -> String {
+        let Location {
+            line,
+            column,
+            underlined,
+            line_start,
+            line_end,
+            ..
+        } = location;
+        let source_line = &self.src[line_start..line_end];
+
+        let line_number = line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let bar = self.paint(BLUE, "|");
+        format!(
+            "{}{} {}:{}:{}\n{} {}\n{} {} {}\n{} {} {}{}\n",
+            gutter,
+            self.paint(BLUE, "-->"),
+            self.file,
+            line_number,
+            column,
+            gutter,
+            bar,
+            self.paint(BLUE, &line_number),
+            bar,
+            source_line,
+            gutter,
+            bar,
+            " ".repeat(column - 1),
+            self.paint(RED, &"^".repeat(underlined)),
+        )
+    }
+
+    fn render_json(&self, diagnostic: &Diagnostic) -> String {
+        let (line, column, length) = match diagnostic.span.and_then(|span| self.locate(span)) {
+            Some(location) => (
+                location.line.to_string(),
+                location.column.to_string(),
+                location.length.to_string(),
+            ),
+            None => ("null".to_string(), "null".to_string(), "null".to_string()),
+        };
+        let notes: Vec<String> = diagnostic.notes.iter().map(|n| json_string(n)).collect();
+        format!(
+            "{{\"file\":{},\"line\":{},\"column\":{},\"length\":{},\"severity\":{},\"code\":{},\"message\":{},\"notes\":[{}]}}\n",
+            json_string(self.file),
+            line,
+            column,
+            length,
+            json_string(diagnostic.severity.name()),
+            json_string(diagnostic.code),
+            json_string(&diagnostic.message),
+            notes.join(","),
+        )
+    }
+}
+
+/// Quote and escape a string as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, ErrorFormat, Renderer, Severity};
+    use crate::scanner::{CodePosition, Span};
+
+    #[test]
+    fn underlines_the_span_in_its_line() {
+        let src = "var a = 1;\nprint a + nil;\n";
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            code: "E0201",
+            message: "nil is not a number".to_string(),
+            span: Some(Span {
+                start: CodePosition {
+                    pos: 19,
+                    line: 1,
+                    column: 8,
+                },
+                length: 1,
+            }),
+            notes: vec!["[line 2] in script".to_string()],
+        };
+        let rendered = Renderer::new("test.lox", src)
+            .with_colour(false)
+            .render(&diagnostic);
+        assert_eq!(
+            rendered,
+            "error: nil is not a number\n \
+             --> test.lox:2:9\n  \
+             |\n\
+             2 | print a + nil;\n  \
+             |         ^\n  \
+             [line 2] in script\n"
+        );
+    }
+
+    #[test]
+    fn spans_not_in_the_source_only_name_the_file() {
+        let renderer = Renderer::new("test.lox", "print \"é\";").with_colour(false);
+        let at = |pos, line, column| Diagnostic {
+            severity: Severity::Error,
+            code: "E0201",
+            message: "oops".to_string(),
+            span: Some(Span {
+                start: CodePosition { pos, line, column },
+                length: 1,
+            }),
+            notes: Vec::new(),
+        };
+        // Past the end, inside the `é`, and on a line the source doesn't have.
+        for diagnostic in &[at(40, 0, 40), at(8, 0, 8), at(2, 3, 0)] {
+            assert_eq!(renderer.render(diagnostic), "error: oops\n--> test.lox\n");
+        }
+    }
+
+    #[test]
+    fn json_has_one_object_per_diagnostic() {
+        let src = "print \"a\" + ;";
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            code: "E0101",
+            message: "Expected an expression".to_string(),
+            span: Some(Span {
+                start: CodePosition {
+                    pos: 12,
+                    line: 0,
+                    column: 12,
+                },
+                length: 1,
+            }),
+            notes: vec!["say \"hi\"".to_string()],
+        };
+        let renderer = Renderer::new("dir\\test.lox", src).with_format(ErrorFormat::Json);
+        assert_eq!(
+            renderer.render(&diagnostic),
+            "{\"file\":\"dir\\\\test.lox\",\"line\":1,\"column\":13,\"length\":1,\
+             \"severity\":\"error\",\"code\":\"E0101\",\"message\":\"Expected an expression\",\
+             \"notes\":[\"say \\\"hi\\\"\"]}\n"
+        );
+    }
+
+    #[test]
+    fn json_counts_characters_not_bytes() {
+        let src = "var é = \"naïve\" + nil;";
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            code: "E0201",
+            message: "\"naïve\" is not a number".to_string(),
+            span: Some(Span {
+                start: CodePosition {
+                    pos: 9,
+                    line: 0,
+                    column: 9,
+                },
+                length: 8,
+            }),
+            notes: Vec::new(),
+        };
+        let renderer = Renderer::new("test.lox", src).with_format(ErrorFormat::Json);
+        assert_eq!(
+            renderer.render(&diagnostic),
+            "{\"file\":\"test.lox\",\"line\":1,\"column\":9,\"length\":7,\
+             \"severity\":\"error\",\"code\":\"E0201\",\"message\":\"\\\"naïve\\\" is not a number\",\
+             \"notes\":[]}\n"
+        );
+    }
+}
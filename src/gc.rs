@@ -0,0 +1,210 @@
+use std::collections::HashSet;
+
+use broom::Handle;
+
+use crate::value::Object;
+
+/// The heap is never collected before it reaches this many bytes.
+const MIN_THRESHOLD: usize = 1024 * 1024;
+/// How many times bigger than what survived the last collection the heap can
+/// grow to before the next.
+const GROWTH_FACTOR: usize = 2;
+
+/// How many of each kind of object are allocated, garbage included until it's
+/// collected.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HeapStats {
+    pub strings: usize,
+    pub functions: usize,
+    pub closures: usize,
+    pub upvalues: usize,
+    pub classes: usize,
+    pub instances: usize,
+    pub bound_methods: usize,
+    pub natives: usize,
+    /// Roughly how many bytes all of them take up.
+    pub bytes: usize,
+    /// How many times the heap has been collected.
+    pub collections: usize,
+}
+
+impl HeapStats {
+    /// The number of objects of every kind.
+    pub fn objects(&self) -> usize {
+        self.strings
+            + self.functions
+            + self.closures
+            + self.upvalues
+            + self.classes
+            + self.instances
+            + self.bound_methods
+            + self.natives
+    }
+
+    /// Every statistic, named as scripts see it.
+    pub fn fields(&self) -> [(&'static str, usize); 10] {
+        [
+            ("strings", self.strings),
+            ("functions", self.functions),
+            ("closures", self.closures),
+            ("upvalues", self.upvalues),
+            ("classes", self.classes),
+            ("instances", self.instances),
+            ("boundMethods", self.bound_methods),
+            ("natives", self.natives),
+            ("bytes", self.bytes),
+            ("collections", self.collections),
+        ]
+    }
+}
+
+/// Where every object lives. Wraps broom's heap to keep track of roughly how
+/// much memory is in use, which is what decides when it's worth collecting.
+pub struct Heap {
+    objects: broom::Heap<Object>,
+    /// Every object allocated, including any freed since the last collection.
+    handles: Vec<Handle<Object>>,
+    /// Roughly how many bytes are allocated, by `Object::size`.
+    bytes: usize,
+    /// How many bytes can be allocated before the next collection.
+    threshold: usize,
+    /// Whether to collect after every allocation, to flush out objects which
+    /// are in use but not reachable from the roots.
+    stress: bool,
+    allocations_since_collection: usize,
+    collections: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: broom::Heap::new(),
+            handles: Vec::new(),
+            bytes: 0,
+            threshold: MIN_THRESHOLD,
+            stress: false,
+            allocations_since_collection: 0,
+            collections: 0,
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::default()
+    }
+
+    pub fn stress(&self) -> bool {
+        self.stress
+    }
+
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    /// Allocate an object. Nothing is collected here, so this is safe to call
+    /// whatever isn't rooted yet; it's up to the owner of the heap to collect
+    /// when `should_collect` says so, at a point where everything live is
+    /// reachable.
+    pub fn insert_temp(&mut self, object: Object) -> Handle<Object> {
+        self.bytes += object.size();
+        self.allocations_since_collection += 1;
+        let handle = self.objects.insert_temp(object);
+        self.handles.push(handle);
+        handle
+    }
+
+    /// Count `bytes` more against an object which has grown since it was
+    /// allocated, so the heap's size stays true between collections.
+    pub fn grew(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+
+    /// The number of objects allocated.
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Roughly how many bytes are allocated.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// How many bytes are allocated, measuring each object afresh rather than
+    /// trusting the running count.
+    #[cfg(test)]
+    pub fn measure(&self) -> usize {
+        self.handles
+            .iter()
+            .map(|handle| unsafe { handle.get_unchecked() }.size())
+            .sum()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        if self.stress {
+            self.allocations_since_collection > 0
+        } else {
+            self.bytes > self.threshold
+        }
+    }
+
+    /// Free every object which can't be reached from `roots`, and let the
+    /// heap grow in proportion to what's left before the next collection.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Handle<Object>>) {
+        let marked = Heap::mark(roots);
+        let freed: usize = self
+            .handles
+            .iter()
+            .filter(|handle| !marked.contains(handle))
+            .map(|handle| unsafe { handle.get_unchecked() }.size())
+            .sum();
+        self.handles.retain(|handle| marked.contains(handle));
+        // Objects don't trace their children for broom, so it only keeps what
+        // was marked here.
+        self.objects.clean_excluding(marked);
+        self.bytes -= freed;
+        self.threshold = (self.bytes * GROWTH_FACTOR).max(MIN_THRESHOLD);
+        self.allocations_since_collection = 0;
+        self.collections += 1;
+    }
+
+    /// Every object reachable from `roots`. The objects still to be visited
+    /// are kept in a worklist rather than on the native stack, however long
+    /// the chains of objects are.
+    fn mark(roots: impl IntoIterator<Item = Handle<Object>>) -> HashSet<Handle<Object>> {
+        let mut marked = HashSet::new();
+        let mut worklist: Vec<Handle<Object>> = roots.into_iter().collect();
+        while let Some(handle) = worklist.pop() {
+            if marked.insert(handle) {
+                unsafe { handle.get_unchecked() }.children(|child| {
+                    if !marked.contains(&child) {
+                        worklist.push(child)
+                    }
+                });
+            }
+        }
+        marked
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            bytes: self.bytes,
+            collections: self.collections,
+            ..HeapStats::default()
+        };
+        for handle in &self.handles {
+            let count = match unsafe { handle.get_unchecked() } {
+                Object::Str(_) => &mut stats.strings,
+                Object::Function(_) => &mut stats.functions,
+                Object::Closure(_) => &mut stats.closures,
+                Object::Upvalue(_) => &mut stats.upvalues,
+                Object::Class(_) => &mut stats.classes,
+                Object::Instance(_) => &mut stats.instances,
+                Object::BoundMethod(_) => &mut stats.bound_methods,
+                Object::Native(_) => &mut stats.natives,
+            };
+            *count += 1;
+        }
+        stats
+    }
+}
@@ -0,0 +1,440 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, stdin, stdout, IsTerminal, Read, Write};
+use std::path::PathBuf;
+
+/// Kept in the user's home directory.
+const HISTORY_FILE: &str = ".drool_history";
+const HISTORY_LIMIT: usize = 1000;
+
+/// A key press, as far as editing is concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    /// Ctrl-U: delete everything before the cursor.
+    KillToStart,
+    /// Ctrl-K: delete everything after the cursor.
+    KillToEnd,
+    /// Ctrl-C.
+    Interrupt,
+    /// Ctrl-D.
+    EndOfInput,
+    /// Anything we don't do anything with.
+    Ignored,
+}
+
+/// Read a single key press, decoding the escape sequences terminals send for
+/// arrows and the like. `None` at the end of input.
+fn read_key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let byte = match read_byte(input)? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+    let key = match byte {
+        1 => Key::Home,
+        2 => Key::Left,
+        3 => Key::Interrupt,
+        4 => Key::EndOfInput,
+        5 => Key::End,
+        6 => Key::Right,
+        8 | 127 => Key::Backspace,
+        9 => Key::Tab,
+        10 | 13 => Key::Enter,
+        11 => Key::KillToEnd,
+        14 => Key::Down,
+        16 => Key::Up,
+        21 => Key::KillToStart,
+        27 => read_escape(input)?,
+        byte if byte < 32 => Key::Ignored,
+        byte => read_char(input, byte)?,
+    };
+    Ok(Some(key))
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// The rest of an escape sequence, after the escape itself.
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    match read_byte(input)? {
+        Some(b'[') | Some(b'O') => {}
+        _ => return Ok(Key::Ignored),
+    }
+    Ok(match read_byte(input)? {
+        Some(b'A') => Key::Up,
+        Some(b'B') => Key::Down,
+        Some(b'C') => Key::Right,
+        Some(b'D') => Key::Left,
+        Some(b'H') => Key::Home,
+        Some(b'F') => Key::End,
+        // Sequences like `ESC [ 3 ~` are numbered, and end with a tilde.
+        Some(digit @ b'0'..=b'9') => {
+            let mut number = vec![digit];
+            loop {
+                match read_byte(input)? {
+                    Some(b'~') => break,
+                    Some(digit @ b'0'..=b'9') => number.push(digit),
+                    _ => return Ok(Key::Ignored),
+                }
+            }
+            match number.as_slice() {
+                b"1" | b"7" => Key::Home,
+                b"3" => Key::Delete,
+                b"4" | b"8" => Key::End,
+                _ => Key::Ignored,
+            }
+        }
+        _ => Key::Ignored,
+    })
+}
+
+/// A character starting with `first`, reading the rest of it if it takes
+/// more than one byte.
+fn read_char(input: &mut impl Read, first: u8) -> io::Result<Key> {
+    let length = match first.leading_ones() {
+        0 => 1,
+        n @ 2..=4 => n as usize,
+        _ => return Ok(Key::Ignored),
+    };
+    let mut bytes = vec![first];
+    for _ in 1..length {
+        match read_byte(input)? {
+            Some(byte) => bytes.push(byte),
+            None => return Ok(Key::Ignored),
+        }
+    }
+    Ok(std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(|s| s.chars().next())
+        .map_or(Key::Ignored, Key::Char))
+}
+
+/// The longest prefix every candidate shares.
+fn common_prefix(candidates: &[String]) -> &str {
+    let mut prefix = match candidates.first() {
+        Some(first) => first.as_str(),
+        None => return "",
+    };
+    for candidate in &candidates[1..] {
+        let shared = prefix
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(prefix.len().min(candidate.len()), |((i, _), _)| i);
+        prefix = &prefix[..shared];
+    }
+    prefix
+}
+
+/// The line being edited.
+#[derive(Default)]
+struct Line {
+    chars: Vec<char>,
+    /// An index into `chars`, which can be one past the end.
+    cursor: usize,
+}
+
+impl Line {
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn insert(&mut self, text: &str) {
+        for c in text.chars() {
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    /// Where the identifier the cursor is at the end of starts.
+    fn word_start(&self) -> usize {
+        self.chars[..self.cursor]
+            .iter()
+            .rposition(|c| !c.is_alphanumeric())
+            .map_or(0, |i| i + 1)
+    }
+
+    /// Redraw the line after `prompt`, leaving the terminal's cursor where
+    /// ours is.
+    fn draw(&self, out: &mut impl Write, prompt: &str) -> io::Result<()> {
+        let before: String = self.chars[..self.cursor].iter().collect();
+        // Rather than work out how wide everything is, write out whatever
+        // comes before the cursor a second time to put it in place.
+        write!(
+            out,
+            "\r{}{}\x1b[K\r{}{}",
+            prompt,
+            self.text(),
+            prompt,
+            before
+        )?;
+        out.flush()
+    }
+}
+
+/// Reads lines from a terminal, with cursor movement, history and tab
+/// completion. When stdin isn't a terminal, lines are read as they come.
+pub struct LineEditor {
+    history: Vec<String>,
+    /// Where history is kept between sessions, if anywhere.
+    history_file: Option<PathBuf>,
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        let history_file = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        let mut history: Vec<String> = history_file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .map(|src| src.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        if history.len() > HISTORY_LIMIT {
+            history.drain(..history.len() - HISTORY_LIMIT);
+            if let Some(file) = &history_file {
+                let _ = fs::write(file, history.join("\n") + "\n");
+            }
+        }
+        LineEditor {
+            history,
+            history_file,
+        }
+    }
+
+    /// Read a line, without its line ending. `None` at the end of input, and
+    /// an `Interrupted` error if the user pressed Ctrl-C. `complete` gives
+    /// every completion of the word before the cursor when Tab is pressed.
+    pub fn read_line<C>(&mut self, prompt: &str, complete: C) -> io::Result<Option<String>>
+    where
+        C: Fn(&str) -> Vec<String>,
+    {
+        if !stdin().is_terminal() || !stdout().is_terminal() {
+            return read_plain(prompt);
+        }
+        let line = self.edit(prompt, complete)?;
+        if let Some(line) = &line {
+            self.remember(line);
+        }
+        Ok(line)
+    }
+
+    #[cfg(not(unix))]
+    fn edit<C>(&mut self, prompt: &str, _complete: C) -> io::Result<Option<String>>
+    where
+        C: Fn(&str) -> Vec<String>,
+    {
+        read_plain(prompt)
+    }
+
+    #[cfg(unix)]
+    fn edit<C>(&mut self, prompt: &str, complete: C) -> io::Result<Option<String>>
+    where
+        C: Fn(&str) -> Vec<String>,
+    {
+        let _raw = raw::RawMode::enable()?;
+        let mut input = stdin().lock();
+        let mut out = stdout();
+        let mut line = Line::default();
+        // Moving through history leaves whatever was being typed here.
+        let mut draft = String::new();
+        let mut position = self.history.len();
+
+        line.draw(&mut out, prompt)?;
+        loop {
+            let key = match read_key(&mut input)? {
+                Some(key) => key,
+                None => return Ok(None),
+            };
+            match key {
+                Key::Char(c) => line.insert(c.encode_utf8(&mut [0; 4])),
+                Key::Enter => {
+                    write!(out, "\r\n")?;
+                    return Ok(Some(line.text()));
+                }
+                Key::Interrupt => {
+                    write!(out, "^C\r\n")?;
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                Key::EndOfInput if line.chars.is_empty() => return Ok(None),
+                Key::EndOfInput | Key::Delete => {
+                    if line.cursor < line.chars.len() {
+                        line.chars.remove(line.cursor);
+                    }
+                }
+                Key::Backspace => {
+                    if line.cursor > 0 {
+                        line.cursor -= 1;
+                        line.chars.remove(line.cursor);
+                    }
+                }
+                Key::Left => line.cursor = line.cursor.saturating_sub(1),
+                Key::Right => line.cursor = (line.cursor + 1).min(line.chars.len()),
+                Key::Home => line.cursor = 0,
+                Key::End => line.cursor = line.chars.len(),
+                Key::KillToStart => {
+                    line.chars.drain(..line.cursor);
+                    line.cursor = 0;
+                }
+                Key::KillToEnd => line.chars.truncate(line.cursor),
+                Key::Up if position > 0 => {
+                    if position == self.history.len() {
+                        draft = line.text();
+                    }
+                    position -= 1;
+                    line.set(&self.history[position]);
+                }
+                Key::Down if position < self.history.len() => {
+                    position += 1;
+                    line.set(self.history.get(position).unwrap_or(&draft));
+                }
+                Key::Tab => {
+                    let start = line.word_start();
+                    let word: String = line.chars[start..line.cursor].iter().collect();
+                    let candidates = complete(&word);
+                    let prefix = common_prefix(&candidates);
+                    if prefix.len() > word.len() {
+                        line.insert(&prefix[word.len()..]);
+                    } else if candidates.len() > 1 {
+                        write!(out, "\r\n{}\r\n", candidates.join("  "))?;
+                    } else {
+                        write!(out, "\x07")?;
+                    }
+                }
+                Key::Up | Key::Down | Key::Ignored => {}
+            }
+            line.draw(&mut out, prompt)?;
+        }
+    }
+
+    /// Add a line to the history, and to the history file. History is only a
+    /// convenience, so failing to save it isn't worth interrupting anyone over.
+    fn remember(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > HISTORY_LIMIT {
+            self.history.remove(0);
+        }
+        if let Some(file) = &self.history_file {
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .and_then(|mut file| writeln!(file, "{}", line));
+        }
+    }
+}
+
+/// Read a line with no editing at all.
+fn read_plain(prompt: &str) -> io::Result<Option<String>> {
+    print!("{}", prompt);
+    stdout().flush()?;
+    let mut line = String::new();
+    if stdin().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let len = line.trim_end_matches(&['\n', '\r'][..]).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+#[cfg(unix)]
+mod raw {
+    use std::io;
+    use std::mem::MaybeUninit;
+
+    /// Keeps the terminal on stdin in raw mode, where we see each key as it's
+    /// pressed and echo it ourselves, until dropped.
+    pub struct RawMode {
+        original: libc::termios,
+    }
+
+    impl RawMode {
+        pub fn enable() -> io::Result<RawMode> {
+            let mut original = MaybeUninit::<libc::termios>::uninit();
+            // SAFETY: tcgetattr fills in the termios if it succeeds.
+            let original = unsafe {
+                if libc::tcgetattr(libc::STDIN_FILENO, original.as_mut_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                original.assume_init()
+            };
+            let mut raw = original;
+            raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+            raw.c_oflag &= !libc::OPOST;
+            raw.c_cflag |= libc::CS8;
+            raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            // SAFETY: `raw` is a valid termios.
+            if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &raw) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original })
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            // SAFETY: `original` came from tcgetattr.
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.original);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{common_prefix, read_key, Key};
+
+    #[test]
+    fn keys_are_decoded_from_escape_sequences_and_utf8() {
+        let mut input: &[u8] = b"\x1b[A\x1b[3~\x1bOHa\xe3\x83\x89\r\x03";
+        let mut keys = Vec::new();
+        while let Some(key) = read_key(&mut input).unwrap() {
+            keys.push(key);
+        }
+        assert_eq!(
+            keys,
+            vec![
+                Key::Up,
+                Key::Delete,
+                Key::Home,
+                Key::Char('a'),
+                Key::Char('ド'),
+                Key::Enter,
+                Key::Interrupt,
+            ]
+        );
+    }
+
+    #[test]
+    fn completion_extends_to_the_common_prefix() {
+        let candidates = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(common_prefix(&candidates(&["print", "printf"])), "print");
+        assert_eq!(common_prefix(&candidates(&["fun", "for"])), "f");
+        assert_eq!(common_prefix(&candidates(&["clock"])), "clock");
+        assert_eq!(common_prefix(&[]), "");
+    }
+}
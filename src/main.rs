@@ -1,61 +1,207 @@
 use std::{
-    env, io,
-    process::{self},
+    env,
+    fs::{self, File},
+    io::{self, stdin, stdout, Write},
+    process,
 };
 
-use chunk::Chunk;
-use op::Op;
+use diagnostic::{Diagnostic, ErrorFormat, Renderer};
 use repl::Repl;
-use vm::VM;
+use vm::{InterpretError, Tracing, VM};
 
 mod chunk;
 mod compiler;
 mod data;
+mod diagnostic;
+mod gc;
+mod line_editor;
 mod op;
 mod repl;
 mod scanner;
 mod value;
 mod vm;
 
-fn repl() {
-    let chunk = Chunk::of(|c| c.operation(Op::Return, 1));
-    Repl::new(VM::new(chunk)).start().expect("Oh noes");
+const USAGE: &str = "\
+Usage: drool [options] [command]
+
+Commands:
+  repl                  Start an interactive session (the default)
+  run <file> [args...]  Run a script, passing it any arguments after it
+  check <file>          Report errors in a script without running it
+  disasm <file>         Print a script's bytecode without running it
+  -e <code> [args...]   Run the given code
+  <file> [args...]      Short for `run <file>`
+
+A file of `-` is read from stdin.
+
+Options, which go before the command:
+  --error-format=human|json  How to report errors (default: human)
+  --print-code               Print each function's bytecode once compiled
+  --trace-exec               Print the stack and each instruction as it runs
+  --gc-log                   Report each garbage collection
+  --gc-stress                Collect garbage after every allocation, as does
+                             setting DROOL_GC_STRESS
+  --trace-file=<file>        Where to print code, traces and GC reports
+                             (default: stdout)
+  -h, --help                 Show this message";
+
+/// Settings which apply whatever the command.
+struct Options {
+    format: ErrorFormat,
+    tracing: Tracing,
+    trace_file: Option<String>,
+    gc_stress: bool,
 }
 
-fn run_file(filename: &str) -> io::Result<()> {
-    let src = std::fs::read_to_string(filename)?;
+impl Options {
+    /// A VM set up as the options ask, exiting if that isn't possible.
+    fn vm(&self) -> VM {
+        let sink = self
+            .trace_file
+            .as_ref()
+            .map(|path| match File::create(path) {
+                Ok(file) => Box::new(file) as Box<dyn Write>,
+                Err(e) => {
+                    eprintln!("Couldn't create {}: {}", path, e);
+                    process::exit(exitcode::CANTCREAT);
+                }
+            });
+        let vm = VM::new().with_tracing(self.tracing, sink);
+        // Without the flag, it's still up to the environment variable.
+        if self.gc_stress {
+            vm.with_gc_stress(true)
+        } else {
+            vm
+        }
+    }
+}
 
-    let chunk = Default::default();
-    let mut vm = VM::new(chunk);
-    let result = vm.interpret(&src);
+/// A script, along with the name to report its errors against.
+struct Source {
+    name: String,
+    src: String,
+}
 
-    match result {
-        Err(vm::InterpretError::Compile(ce)) => {
-            println!("Compilation failed: {:?}", ce);
-            process::exit(exitcode::DATAERR);
+impl Source {
+    /// Read the script at `path`, or stdin if it's `-`, exiting if it can't be
+    /// read.
+    fn read(path: &str) -> Source {
+        let (name, result) = match path {
+            "-" => ("<stdin>", io::read_to_string(stdin())),
+            _ => (path, fs::read_to_string(path)),
+        };
+        match result {
+            Ok(src) => Source {
+                name: name.to_string(),
+                src,
+            },
+            Err(e) => {
+                eprintln!("Couldn't read {}: {}", name, e);
+                process::exit(exitcode::NOINPUT);
+            }
         }
-        Err(vm::InterpretError::Runtime(rte)) => {
-            println!("Runtime error: {}", rte);
-            process::exit(exitcode::SOFTWARE);
+    }
+}
+
+fn repl(options: &Options) {
+    Repl::new(options.vm()).start().expect("Oh noes");
+}
+
+/// Report an error against the source it came from, and exit.
+fn fail(source: &Source, format: ErrorFormat, error: InterpretError) -> ! {
+    let renderer = Renderer::new(&source.name, &source.src).with_format(format);
+    for diagnostic in Diagnostic::from_interpret_error(&error) {
+        eprint!("{}", renderer.render(&diagnostic));
+    }
+    process::exit(match error {
+        InterpretError::Compile(_) => exitcode::DATAERR,
+        InterpretError::Runtime { .. } => exitcode::SOFTWARE,
+    });
+}
+
+fn run(source: &Source, args: &[String], options: &Options) {
+    let mut vm = options.vm();
+    vm.define_args(args.to_vec());
+    if let Err(error) = vm.interpret(&source.src) {
+        fail(source, options.format, error);
+    }
+}
+
+fn check(source: &Source, options: &Options) {
+    if let Err(error) = options.vm().compile(&source.src) {
+        fail(source, options.format, error);
+    }
+}
+
+fn disasm(source: &Source, options: &Options) {
+    match options.vm().compile(&source.src) {
+        Ok(script) => {
+            if let Err(e) = script.disassemble(&mut stdout().lock()) {
+                eprintln!("Couldn't write disassembly: {}", e);
+                process::exit(exitcode::IOERR);
+            }
         }
-        Ok(_) => (),
-    };
-    Ok(())
+        Err(error) => fail(source, options.format, error),
+    }
 }
 
-fn switch() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1])?,
-        _ => {
-            eprintln!("Usage: drool [file]");
-            process::exit(exitcode::USAGE);
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(exitcode::USAGE);
+}
+
+fn switch() {
+    let mut options = Options {
+        format: ErrorFormat::Human,
+        tracing: Tracing::default(),
+        trace_file: None,
+        gc_stress: false,
+    };
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // Anything after the command belongs to it, or to the script it runs.
+    let count = args
+        .iter()
+        .take_while(|arg| arg.starts_with("--") || *arg == "-h")
+        .count();
+    for option in args.drain(..count) {
+        match option.as_str() {
+            "-h" | "--help" => return println!("{}", USAGE),
+            "--print-code" => options.tracing.print_code = true,
+            "--trace-exec" => options.tracing.trace_exec = true,
+            "--gc-log" => options.tracing.gc_log = true,
+            "--gc-stress" => options.gc_stress = true,
+            _ => {
+                if let Some(name) = option.strip_prefix("--error-format=") {
+                    options.format = ErrorFormat::parse(name).unwrap_or_else(|| usage());
+                } else if let Some(path) = option.strip_prefix("--trace-file=") {
+                    options.trace_file = Some(path.to_string());
+                } else {
+                    usage();
+                }
+            }
+        }
+    }
+
+    match args.as_slice() {
+        [] => repl(&options),
+        [command] if command == "repl" => repl(&options),
+        [command, file, rest @ ..] if command == "run" => run(&Source::read(file), rest, &options),
+        [command, file] if command == "check" => check(&Source::read(file), &options),
+        [command, file] if command == "disasm" => disasm(&Source::read(file), &options),
+        [flag, code, rest @ ..] if flag == "-e" => {
+            let source = Source {
+                name: "<eval>".to_string(),
+                src: code.to_string(),
+            };
+            run(&source, rest, &options)
+        }
+        [file, rest @ ..] if file == "-" || !file.starts_with('-') => {
+            run(&Source::read(file), rest, &options)
         }
+        _ => usage(),
     }
-    Ok(())
 }
 
-fn main() -> io::Result<()> {
+fn main() {
     switch()
 }
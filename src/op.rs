@@ -1,6 +1,18 @@
 use crate::{chunk::Chunk, data::FromU24Bytes};
-use crate::{data::u24, value::Value};
+use crate::{
+    data::u24,
+    value::{Object, Value},
+};
 use std::convert::TryInto;
+use std::io::{self, Write};
+
+/// Width of a jump instruction: the opcode plus a 16-bit offset.
+pub const JUMP_COST: usize = 3;
+
+#[inline]
+unsafe fn read_u16(ptr: *const u8) -> u16 {
+    u16::from_le_bytes([*ptr, *ptr.add(1)])
+}
 
 // The actual constant map, for use in the real, scary world.
 // Rust doesn't yet me let associate these with the actual enum entries.
@@ -28,6 +40,27 @@ impl OpCode {
     pub const LESS: u8         = 0x0E;
     pub const PRINT: u8        = 0x0F;
     pub const POP: u8          = 0x10;
+    pub const DEFINE_GLOBAL: u8 = 0x11;
+    pub const GET_GLOBAL: u8   = 0x12;
+    pub const SET_GLOBAL: u8   = 0x13;
+    pub const GET_LOCAL: u8    = 0x14;
+    pub const SET_LOCAL: u8    = 0x15;
+    pub const JUMP: u8         = 0x16;
+    pub const JUMP_IF_FALSE: u8 = 0x17;
+    pub const LOOP: u8         = 0x18;
+    pub const CALL: u8         = 0x19;
+    pub const CLOSURE: u8      = 0x1A;
+    pub const GET_UPVALUE: u8  = 0x1B;
+    pub const SET_UPVALUE: u8  = 0x1C;
+    pub const CLOSE_UPVALUE: u8 = 0x1D;
+    pub const CLASS: u8        = 0x1E;
+    pub const GET_PROPERTY: u8 = 0x1F;
+    pub const SET_PROPERTY: u8 = 0x20;
+    pub const METHOD: u8       = 0x21;
+    pub const INVOKE: u8       = 0x22;
+    pub const INHERIT: u8      = 0x23;
+    pub const GET_SUPER: u8    = 0x24;
+    pub const SUPER_INVOKE: u8 = 0x25;
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -35,26 +68,46 @@ impl OpCode {
 // clone `Op`s in production code, since I might introduce
 // performance regressions.
 #[cfg_attr(test, derive(Clone))]
-
 pub enum Op {
     //               // CODE, COST
-    Return,          // 0x00
-    ConstSmol(u8),   // 0x01, 2
-    ConstThicc(u24), // 0x02, 4
-    Negate,          // 0x03
-    Add,             // 0x04
-    Subtract,        // 0x05
-    Multiply,        // 0x06
-    Divide,          // 0x07
-    Nil,             // 0x08
-    True,            // 0x09
-    False,           // 0x0A
-    Not,             // 0x0B
-    Equal,           // 0x0C
-    Greater,         // 0x0D
-    Less,            // 0x0E
-    Print,           // 0x0F
-    Pop,             // 0x10
+    Return,               // 0x00
+    ConstSmol(u8),        // 0x01, 2
+    ConstThicc(u24),      // 0x02, 4
+    Negate,               // 0x03
+    Add,                  // 0x04
+    Subtract,             // 0x05
+    Multiply,             // 0x06
+    Divide,               // 0x07
+    Nil,                  // 0x08
+    True,                 // 0x09
+    False,                // 0x0A
+    Not,                  // 0x0B
+    Equal,                // 0x0C
+    Greater,              // 0x0D
+    Less,                 // 0x0E
+    Print,                // 0x0F
+    Pop,                  // 0x10
+    DefineGlobal(u24),    // 0x11, 4
+    GetGlobal(u24),       // 0x12, 4
+    SetGlobal(u24),       // 0x13, 4
+    GetLocal(u8),         // 0x14, 2
+    SetLocal(u8),         // 0x15, 2
+    Jump(u16),            // 0x16, 3
+    JumpIfFalse(u16),     // 0x17, 3
+    Loop(u16),            // 0x18, 3
+    Call(u8),             // 0x19, 2
+    Closure(u24),         // 0x1A, 4
+    GetUpvalue(u8),       // 0x1B, 2
+    SetUpvalue(u8),       // 0x1C, 2
+    CloseUpvalue,         // 0x1D
+    Class(u24),           // 0x1E, 4
+    GetProperty(u24),     // 0x1F, 4
+    SetProperty(u24),     // 0x20, 4
+    Method(u24),          // 0x21, 4
+    Invoke(u24, u8),      // 0x22, 5
+    Inherit,              // 0x23
+    GetSuper(u24),        // 0x24, 4
+    SuperInvoke(u24, u8), // 0x25, 5
 }
 
 impl Op {
@@ -81,6 +134,27 @@ impl Op {
             OpCode::LESS => Op::Less,
             OpCode::PRINT => Op::Print,
             OpCode::POP => Op::Pop,
+            OpCode::DEFINE_GLOBAL => Op::DefineGlobal(u24::from_u8_ptr(ptr.add(1))),
+            OpCode::GET_GLOBAL => Op::GetGlobal(u24::from_u8_ptr(ptr.add(1))),
+            OpCode::SET_GLOBAL => Op::SetGlobal(u24::from_u8_ptr(ptr.add(1))),
+            OpCode::GET_LOCAL => Op::GetLocal(*ptr.add(1)),
+            OpCode::SET_LOCAL => Op::SetLocal(*ptr.add(1)),
+            OpCode::JUMP => Op::Jump(read_u16(ptr.add(1))),
+            OpCode::JUMP_IF_FALSE => Op::JumpIfFalse(read_u16(ptr.add(1))),
+            OpCode::LOOP => Op::Loop(read_u16(ptr.add(1))),
+            OpCode::CALL => Op::Call(*ptr.add(1)),
+            OpCode::CLOSURE => Op::Closure(u24::from_u8_ptr(ptr.add(1))),
+            OpCode::GET_UPVALUE => Op::GetUpvalue(*ptr.add(1)),
+            OpCode::SET_UPVALUE => Op::SetUpvalue(*ptr.add(1)),
+            OpCode::CLOSE_UPVALUE => Op::CloseUpvalue,
+            OpCode::CLASS => Op::Class(u24::from_u8_ptr(ptr.add(1))),
+            OpCode::GET_PROPERTY => Op::GetProperty(u24::from_u8_ptr(ptr.add(1))),
+            OpCode::SET_PROPERTY => Op::SetProperty(u24::from_u8_ptr(ptr.add(1))),
+            OpCode::METHOD => Op::Method(u24::from_u8_ptr(ptr.add(1))),
+            OpCode::INVOKE => Op::Invoke(u24::from_u8_ptr(ptr.add(1)), *ptr.add(4)),
+            OpCode::INHERIT => Op::Inherit,
+            OpCode::GET_SUPER => Op::GetSuper(u24::from_u8_ptr(ptr.add(1))),
+            OpCode::SUPER_INVOKE => Op::SuperInvoke(u24::from_u8_ptr(ptr.add(1)), *ptr.add(4)),
             _ => panic!("Corrupt bytecode"),
         };
         *ptr = ptr.add(op.cost());
@@ -112,6 +186,86 @@ impl Op {
             Op::Less => buffer.push(OpCode::LESS),
             Op::Print => buffer.push(OpCode::PRINT),
             Op::Pop => buffer.push(OpCode::POP),
+            Op::DefineGlobal(i) => {
+                buffer.push(OpCode::DEFINE_GLOBAL);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::GetGlobal(i) => {
+                buffer.push(OpCode::GET_GLOBAL);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::SetGlobal(i) => {
+                buffer.push(OpCode::SET_GLOBAL);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::GetLocal(slot) => {
+                buffer.push(OpCode::GET_LOCAL);
+                buffer.push(slot);
+            }
+            Op::SetLocal(slot) => {
+                buffer.push(OpCode::SET_LOCAL);
+                buffer.push(slot);
+            }
+            Op::Jump(offset) => {
+                buffer.push(OpCode::JUMP);
+                offset.to_le_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::JumpIfFalse(offset) => {
+                buffer.push(OpCode::JUMP_IF_FALSE);
+                offset.to_le_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::Loop(offset) => {
+                buffer.push(OpCode::LOOP);
+                offset.to_le_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::Call(arg_count) => {
+                buffer.push(OpCode::CALL);
+                buffer.push(arg_count);
+            }
+            Op::Closure(i) => {
+                buffer.push(OpCode::CLOSURE);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::GetUpvalue(slot) => {
+                buffer.push(OpCode::GET_UPVALUE);
+                buffer.push(slot);
+            }
+            Op::SetUpvalue(slot) => {
+                buffer.push(OpCode::SET_UPVALUE);
+                buffer.push(slot);
+            }
+            Op::CloseUpvalue => buffer.push(OpCode::CLOSE_UPVALUE),
+            Op::Class(i) => {
+                buffer.push(OpCode::CLASS);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::GetProperty(i) => {
+                buffer.push(OpCode::GET_PROPERTY);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::SetProperty(i) => {
+                buffer.push(OpCode::SET_PROPERTY);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::Method(i) => {
+                buffer.push(OpCode::METHOD);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::Invoke(i, arg_count) => {
+                buffer.push(OpCode::INVOKE);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+                buffer.push(arg_count);
+            }
+            Op::Inherit => buffer.push(OpCode::INHERIT),
+            Op::GetSuper(i) => {
+                buffer.push(OpCode::GET_SUPER);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+            }
+            Op::SuperInvoke(i, arg_count) => {
+                buffer.push(OpCode::SUPER_INVOKE);
+                i.to_bytes().iter().for_each(|b| buffer.push(*b));
+                buffer.push(arg_count);
+            }
         }
     }
 
@@ -136,6 +290,27 @@ impl Op {
             Op::Less => 1,
             Op::Print => 1,
             Op::Pop => 1,
+            Op::DefineGlobal(_) => 4,
+            Op::GetGlobal(_) => 4,
+            Op::SetGlobal(_) => 4,
+            Op::GetLocal(_) => 2,
+            Op::SetLocal(_) => 2,
+            Op::Jump(_) => JUMP_COST,
+            Op::JumpIfFalse(_) => JUMP_COST,
+            Op::Loop(_) => JUMP_COST,
+            Op::Call(_) => 2,
+            Op::Closure(_) => 4,
+            Op::GetUpvalue(_) => 2,
+            Op::SetUpvalue(_) => 2,
+            Op::CloseUpvalue => 1,
+            Op::Class(_) => 4,
+            Op::GetProperty(_) => 4,
+            Op::SetProperty(_) => 4,
+            Op::Method(_) => 4,
+            Op::Invoke(_, _) => 5,
+            Op::Inherit => 1,
+            Op::GetSuper(_) => 4,
+            Op::SuperInvoke(_, _) => 5,
         }
     }
 
@@ -158,50 +333,152 @@ impl Op {
             Op::Less => "OP_LESS",
             Op::Print => "OP_PRINT",
             Op::Pop => "OP_POP",
+            Op::DefineGlobal(_) => "OP_DEFINE_GLOBAL",
+            Op::GetGlobal(_) => "OP_GET_GLOBAL",
+            Op::SetGlobal(_) => "OP_SET_GLOBAL",
+            Op::GetLocal(_) => "OP_GET_LOCAL",
+            Op::SetLocal(_) => "OP_SET_LOCAL",
+            Op::Jump(_) => "OP_JUMP",
+            Op::JumpIfFalse(_) => "OP_JUMP_IF_FALSE",
+            Op::Loop(_) => "OP_LOOP",
+            Op::Call(_) => "OP_CALL",
+            Op::Closure(_) => "OP_CLOSURE",
+            Op::GetUpvalue(_) => "OP_GET_UPVALUE",
+            Op::SetUpvalue(_) => "OP_SET_UPVALUE",
+            Op::CloseUpvalue => "OP_CLOSE_UPVALUE",
+            Op::Class(_) => "OP_CLASS",
+            Op::GetProperty(_) => "OP_GET_PROPERTY",
+            Op::SetProperty(_) => "OP_SET_PROPERTY",
+            Op::Method(_) => "OP_METHOD",
+            Op::Invoke(_, _) => "OP_INVOKE",
+            Op::Inherit => "OP_INHERIT",
+            Op::GetSuper(_) => "OP_GET_SUPER",
+            Op::SuperInvoke(_, _) => "OP_SUPER_INVOKE",
         }
     }
 
-    pub fn print(&self, chunk: &Chunk, op_index: usize, pos: usize) {
-        print!("{:0>4} ", pos);
-        if op_index > 0 && chunk.get_line(op_index) == chunk.get_line(op_index - 1) {
-            print!("   | ");
+    /// Write a line of disassembly for this instruction, found at `pos` in `chunk`.
+    pub fn print(&self, out: &mut dyn Write, chunk: &Chunk, pos: usize) -> io::Result<()> {
+        write!(out, "{:0>4} ", pos)?;
+        // The byte before this instruction belongs to the previous one.
+        if pos > 0 && chunk.get_line(pos) == chunk.get_line(pos - 1) {
+            write!(out, "   | ")?;
         } else {
-            print!("{:>4} ", chunk.get_line(op_index));
+            write!(out, "{:>4} ", chunk.get_line(pos))?;
         }
         match self {
-            Self::Return => self.simple_instruction(),
+            Self::Return => self.simple_instruction(out),
             Self::ConstSmol(i) => {
                 let val_index: usize = (*i).into();
-                self.constant_instruction(val_index, chunk.get_constant(val_index))
+                self.constant_instruction(out, val_index, chunk.get_constant(val_index))
             }
             Self::ConstThicc(i) => {
                 let val_index: usize = i.to_usize();
-                self.constant_instruction(val_index, chunk.get_constant(val_index))
+                self.constant_instruction(out, val_index, chunk.get_constant(val_index))
+            }
+            Self::Negate => self.simple_instruction(out),
+            Self::Add => self.simple_instruction(out),
+            Self::Subtract => self.simple_instruction(out),
+            Self::Multiply => self.simple_instruction(out),
+            Self::Divide => self.simple_instruction(out),
+            Self::Nil => self.simple_instruction(out),
+            Self::True => self.simple_instruction(out),
+            Self::False => self.simple_instruction(out),
+            Self::Not => self.simple_instruction(out),
+            Self::Equal => self.simple_instruction(out),
+            Self::Greater => self.simple_instruction(out),
+            Self::Less => self.simple_instruction(out),
+            Self::Print => self.simple_instruction(out),
+            Self::Pop => self.simple_instruction(out),
+            Self::DefineGlobal(i)
+            | Self::GetGlobal(i)
+            | Self::SetGlobal(i)
+            | Self::Class(i)
+            | Self::GetProperty(i)
+            | Self::SetProperty(i)
+            | Self::Method(i)
+            | Self::GetSuper(i) => {
+                let val_index: usize = i.to_usize();
+                self.constant_instruction(out, val_index, chunk.get_constant(val_index))
+            }
+            Self::GetLocal(slot)
+            | Self::SetLocal(slot)
+            | Self::Call(slot)
+            | Self::GetUpvalue(slot)
+            | Self::SetUpvalue(slot) => self.byte_instruction(out, *slot),
+            Self::Closure(i) => {
+                let val_index: usize = i.to_usize();
+                let value = chunk.get_constant(val_index);
+                self.constant_instruction(out, val_index, value)?;
+                self.upvalue_refs(out, pos, value)
+            }
+            Self::CloseUpvalue | Self::Inherit => self.simple_instruction(out),
+            Self::Invoke(i, arg_count) | Self::SuperInvoke(i, arg_count) => {
+                let val_index: usize = i.to_usize();
+                self.invoke_instruction(out, val_index, *arg_count, chunk.get_constant(val_index))
+            }
+            Self::Jump(offset) | Self::JumpIfFalse(offset) => {
+                self.jump_instruction(out, pos, pos + self.cost() + usize::from(*offset))
+            }
+            Self::Loop(offset) => {
+                self.jump_instruction(out, pos, pos + self.cost() - usize::from(*offset))
+            }
+        }
+    }
+
+    fn simple_instruction(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", self.name())
+    }
+
+    fn byte_instruction(&self, out: &mut dyn Write, operand: u8) -> io::Result<()> {
+        writeln!(out, "{:<16} {:>4}", self.name(), operand)
+    }
+
+    fn invoke_instruction(
+        &self,
+        out: &mut dyn Write,
+        index: usize,
+        arg_count: u8,
+        name: &Value,
+    ) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:<16} ({} args) {:>4} {}",
+            self.name(),
+            arg_count,
+            index,
+            name
+        )
+    }
+
+    /// List what a closure captures, under its `OP_CLOSURE`.
+    fn upvalue_refs(&self, out: &mut dyn Write, pos: usize, function: &Value) -> io::Result<()> {
+        if let Value::Obj(handle) = function {
+            if let Object::Function(function) = unsafe { handle.get_unchecked() } {
+                for upvalue in &function.upvalues {
+                    let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                    writeln!(
+                        out,
+                        "{:0>4}    |                     {} {}",
+                        pos, kind, upvalue.index
+                    )?;
+                }
             }
-            Self::Negate => self.simple_instruction(),
-            Self::Add => self.simple_instruction(),
-            Self::Subtract => self.simple_instruction(),
-            Self::Multiply => self.simple_instruction(),
-            Self::Divide => self.simple_instruction(),
-            Self::Nil => self.simple_instruction(),
-            Self::True => self.simple_instruction(),
-            Self::False => self.simple_instruction(),
-            Self::Not => self.simple_instruction(),
-            Self::Equal => self.simple_instruction(),
-            Self::Greater => self.simple_instruction(),
-            Self::Less => self.simple_instruction(),
-            Self::Print => self.simple_instruction(),
-            Self::Pop => self.simple_instruction(),
         }
+        Ok(())
     }
 
-    fn simple_instruction(&self) {
-        println!("{}", self.name());
+    fn jump_instruction(&self, out: &mut dyn Write, pos: usize, target: usize) -> io::Result<()> {
+        writeln!(out, "{:<16} {:>4} -> {}", self.name(), pos, target)
     }
 
-    fn constant_instruction(&self, index: usize, value: &Value) {
-        print!("{:<16} {:>4} {}", self.name(), index, value);
-        println!();
+    fn constant_instruction(
+        &self,
+        out: &mut dyn Write,
+        index: usize,
+        value: &Value,
+    ) -> io::Result<()> {
+        writeln!(out, "{:<16} {:>4} {}", self.name(), index, value)
     }
 
     #[allow(non_snake_case)]
@@ -235,25 +512,28 @@ mod tests {
     use std::convert::TryInto;
 
     use super::Op;
+    use crate::data::u24;
     use quickcheck::{Arbitrary, Gen};
     use quickcheck_macros::quickcheck;
 
+    fn arbitrary_u24<G: Gen>(g: &mut G) -> u24 {
+        let v = g.next_u32() & 0xFF_FF_FF;
+        v.try_into().unwrap()
+    }
+
     impl Arbitrary for Op {
         fn arbitrary<G>(g: &mut G) -> Self
         where
             G: Gen,
         {
-            let n = g.next_u32() & 0x10;
+            let n = g.next_u32() % 0x26;
             match n {
                 0x00 => Op::Return,
                 0x01 => {
                     let v = g.next_u32() & 0xFF;
                     Op::ConstSmol(v.try_into().unwrap())
                 }
-                0x02 => {
-                    let v = g.next_u32() & 0xFF_FF_FF;
-                    Op::ConstThicc(v.try_into().unwrap())
-                }
+                0x02 => Op::ConstThicc(arbitrary_u24(g)),
                 0x03 => Op::Negate,
                 0x04 => Op::Add,
                 0x05 => Op::Subtract,
@@ -268,6 +548,29 @@ mod tests {
                 0x0E => Op::Less,
                 0x0F => Op::Print,
                 0x10 => Op::Pop,
+                0x11 => Op::DefineGlobal(arbitrary_u24(g)),
+                0x12 => Op::GetGlobal(arbitrary_u24(g)),
+                0x13 => Op::SetGlobal(arbitrary_u24(g)),
+                0x14 => Op::GetLocal((g.next_u32() & 0xFF).try_into().unwrap()),
+                0x15 => Op::SetLocal((g.next_u32() & 0xFF).try_into().unwrap()),
+                0x16 => Op::Jump((g.next_u32() & 0xFF_FF).try_into().unwrap()),
+                0x17 => Op::JumpIfFalse((g.next_u32() & 0xFF_FF).try_into().unwrap()),
+                0x18 => Op::Loop((g.next_u32() & 0xFF_FF).try_into().unwrap()),
+                0x19 => Op::Call((g.next_u32() & 0xFF).try_into().unwrap()),
+                0x1A => Op::Closure(arbitrary_u24(g)),
+                0x1B => Op::GetUpvalue((g.next_u32() & 0xFF).try_into().unwrap()),
+                0x1C => Op::SetUpvalue((g.next_u32() & 0xFF).try_into().unwrap()),
+                0x1D => Op::CloseUpvalue,
+                0x1E => Op::Class(arbitrary_u24(g)),
+                0x1F => Op::GetProperty(arbitrary_u24(g)),
+                0x20 => Op::SetProperty(arbitrary_u24(g)),
+                0x21 => Op::Method(arbitrary_u24(g)),
+                0x22 => Op::Invoke(arbitrary_u24(g), (g.next_u32() & 0xFF).try_into().unwrap()),
+                0x23 => Op::Inherit,
+                0x24 => Op::GetSuper(arbitrary_u24(g)),
+                0x25 => {
+                    Op::SuperInvoke(arbitrary_u24(g), (g.next_u32() & 0xFF).try_into().unwrap())
+                }
                 _ => {
                     panic!("Did you mask correctly? I'm guessing you didn't mask correctly. :bonk:")
                 }
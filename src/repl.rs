@@ -1,13 +1,53 @@
-use std::io::{self, stdout, Write};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, stdout};
+use std::time::Instant;
 
-use crate::vm::VM;
+use crate::compiler::{CompileError, SyntaxError};
+use crate::diagnostic::{Diagnostic, Renderer};
+use crate::line_editor::LineEditor;
+use crate::scanner::{ScanError, ScanErrorValue, Scanner, SourceId, TokenType, KEYWORDS};
+use crate::value::Value;
+use crate::vm::{InterpretError, VM};
 
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = "... ";
+
+const HELP: &str = "\
+:dis          disassemble the last input
+:stack        show the stack as the last runtime error left it
+:heap         show what's allocated, by kind
+:load <file>  run a file in this session
+:reset        forget everything defined so far
+:time         toggle timing of each evaluation
+:help         show this message";
+
+/// A read-eval-print loop. Every line runs in the same VM, so it can use
+/// whatever earlier lines defined.
 pub struct Repl {
     vm: VM,
+    editor: LineEditor,
+    /// Whether to report how long each evaluation takes.
+    timing: bool,
+    /// Everything run so far which compiled, since functions it defined can
+    /// fail when called from later input.
+    inputs: HashMap<SourceId, Input>,
+}
+
+/// A piece of source the REPL has run, and the name to report its errors
+/// against.
+struct Input {
+    name: String,
+    src: String,
 }
 impl Repl {
     pub fn new(vm: VM) -> Repl {
-        Repl { vm }
+        Repl {
+            vm,
+            editor: LineEditor::new(),
+            timing: false,
+            inputs: HashMap::new(),
+        }
     }
 
     pub fn start(&mut self) -> io::Result<()> {
@@ -15,18 +55,229 @@ impl Repl {
         println!();
         println!("ドルール。");
         println!();
+        let mut input = String::new();
         loop {
-            print!("> ");
-            stdout().flush()?;
-            let line: String = text_io::read!("{}\n");
-            if line.is_empty() {
-                println!();
-                return Ok(());
+            let prompt = if input.is_empty() {
+                PROMPT
+            } else {
+                CONTINUATION_PROMPT
+            };
+            let vm = &self.vm;
+            let line = match self.editor.read_line(prompt, |word| completions(vm, word)) {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    println!();
+                    return Ok(());
+                }
+                // Ctrl-C throws away whatever's been typed so far.
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    input.clear();
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if input.is_empty() && line.starts_with(':') {
+                self.command(line.trim())?;
+                continue;
+            }
+            input.push_str(&line);
+            input.push('\n');
+            if input.trim().is_empty() {
+                input.clear();
+                continue;
             }
-            match self.vm.interpret(line.as_str()) {
-                Ok(()) => {}
-                Err(e) => println!("{:?}", e),
+            if unclosed(&input) {
+                continue;
             }
+
+            let start = Instant::now();
+            let result = self.run("<repl>", &input, VM::evaluate);
+            let elapsed = start.elapsed();
+            match &result {
+                // The compiler ran out of input, so there's more to come.
+                Err(InterpretError::Compile(errors)) if ends_early(errors) => continue,
+                _ if self.timing => eprintln!("({:?})", elapsed),
+                _ => {}
+            }
+            match result {
+                // Statements evaluate to nil, which isn't worth showing.
+                Ok(Value::Nil) => {}
+                Ok(value) => println!("{}", value.repr()),
+                Err(error) => self.report("<repl>", &input, &error),
+            }
+            input.clear();
+        }
+    }
+
+    /// Run `src` with `run`, keeping it if it compiles.
+    fn run<A>(
+        &mut self,
+        name: &str,
+        src: &str,
+        run: impl FnOnce(&mut VM, &str) -> Result<A, InterpretError>,
+    ) -> Result<A, InterpretError> {
+        let source = self.vm.next_source();
+        let result = run(&mut self.vm, src);
+        if !matches!(result, Err(InterpretError::Compile(_))) {
+            let input = Input {
+                name: name.to_string(),
+                src: src.to_string(),
+            };
+            self.inputs.insert(source, input);
+        }
+        result
+    }
+
+    /// The input a runtime error came from, which may be earlier than the one
+    /// being run.
+    fn input_of(&self, error: &InterpretError) -> Option<&Input> {
+        match error {
+            InterpretError::Runtime { source, .. } => self.inputs.get(source),
+            InterpretError::Compile(_) => None,
         }
     }
+
+    /// Report an error from running `src`, or from the earlier input its span
+    /// is in.
+    fn report(&self, name: &str, src: &str, error: &InterpretError) {
+        let (name, src) = match self.input_of(error) {
+            Some(input) => (input.name.as_str(), input.src.as_str()),
+            None => (name, src),
+        };
+        let renderer = Renderer::new(name, src);
+        for diagnostic in Diagnostic::from_interpret_error(error) {
+            eprint!("{}", renderer.render(&diagnostic));
+        }
+    }
+
+    /// Run a `:` command.
+    fn command(&mut self, command: &str) -> io::Result<()> {
+        let (name, arg) = match command.find(char::is_whitespace) {
+            Some(i) => (&command[..i], command[i..].trim()),
+            None => (command, ""),
+        };
+        match (name, arg) {
+            (":dis", "") => match self.vm.last_script() {
+                Some(script) => script.disassemble(&mut stdout())?,
+                None => eprintln!("Nothing has been run yet."),
+            },
+            (":stack", "") => {
+                if self.vm.stack().is_empty() {
+                    println!("(empty)");
+                } else {
+                    println!("{}", self.vm.stack());
+                }
+            }
+            (":heap", "") => {
+                let stats = self.vm.heap_stats();
+                println!("{:<14} {}", "objects", stats.objects());
+                for (name, n) in stats.fields().iter() {
+                    println!("{:<14} {}", name, n);
+                }
+            }
+            (":load", "") => eprintln!("Usage: :load <file>"),
+            (":load", file) => match fs::read_to_string(file) {
+                Ok(src) => {
+                    if let Err(error) = self.run(file, &src, VM::interpret) {
+                        self.report(file, &src, &error);
+                    }
+                }
+                Err(e) => eprintln!("Couldn't read {}: {}", file, e),
+            },
+            (":reset", "") => {
+                self.vm.reset();
+                self.inputs.clear();
+            }
+            (":time", "") => {
+                self.timing = !self.timing;
+                println!("Timing {}.", if self.timing { "on" } else { "off" });
+            }
+            (":help", "") => println!("{}", HELP),
+            _ => eprintln!("Unknown command {}. Try :help.", command),
+        }
+        Ok(())
+    }
+}
+
+/// The keywords and globals which complete `word`.
+fn completions(vm: &VM, word: &str) -> Vec<String> {
+    if word.is_empty() {
+        return Vec::new();
+    }
+    let mut names: Vec<String> = KEYWORDS
+        .iter()
+        .map(|(keyword, _)| *keyword)
+        .chain(vm.global_names())
+        .filter(|name| name.starts_with(word))
+        .map(str::to_string)
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Whether `src` leaves a string, brace or parenthesis open.
+fn unclosed(src: &str) -> bool {
+    let mut depth = 0_i32;
+    for token in Scanner::new(src) {
+        match token {
+            Ok(token) => match token.typ {
+                TokenType::LeftBrace | TokenType::LeftParen => depth += 1,
+                TokenType::RightBrace | TokenType::RightParen => depth -= 1,
+                _ => {}
+            },
+            Err(ScanError {
+                value: ScanErrorValue::UnterminatedString(_),
+                ..
+            }) => return true,
+            Err(_) => {}
+        }
+    }
+    depth > 0
+}
+
+fn ends_early(errors: &[CompileError]) -> bool {
+    matches!(
+        errors.last(),
+        Some(CompileError::Syntax(SyntaxError::UnexpectedEOF { .. }))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{completions, unclosed, Repl};
+    use crate::value::Value;
+    use crate::vm::{InterpretError, VM};
+
+    #[test]
+    fn open_strings_and_brackets_are_unclosed() {
+        assert!(unclosed("fun f() {"));
+        assert!(unclosed("print (1 +"));
+        assert!(unclosed("print \"two\nlines"));
+        assert!(!unclosed("fun f() { print \"{\"; } // {"));
+        assert!(!unclosed("}"));
+    }
+
+    #[test]
+    fn keywords_and_globals_complete() {
+        let mut vm = VM::new();
+        vm.interpret("var classic = 1;").unwrap();
+        assert_eq!(completions(&vm, "cl"), vec!["class", "classic", "clock"]);
+        assert!(completions(&vm, "").is_empty());
+    }
+
+    #[test]
+    fn errors_are_shown_against_the_input_they_came_from() {
+        let mut repl = Repl::new(VM::new().with_output(Box::new(io::sink())));
+        let define = "fun f() {\n  return -nil;\n}\n";
+        assert_eq!(repl.run("<repl>", define, VM::evaluate), Ok(Value::Nil));
+        let error = match repl.run("<repl>", "print \"ééé\"; f();\n", VM::evaluate) {
+            Err(error @ InterpretError::Runtime { .. }) => error,
+            other => panic!("Expected a runtime error, got {:?}", other),
+        };
+        let input = repl.input_of(&error).expect("Both inputs compiled");
+        assert_eq!(input.src, define);
+    }
 }
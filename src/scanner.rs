@@ -94,6 +94,27 @@ impl Display for TokenType {
     }
 }
 
+/// Reserved words, which scan as their own token types rather than as
+/// identifiers.
+pub const KEYWORDS: [(&str, TokenType); 16] = [
+    ("and", TokenType::And),
+    ("class", TokenType::Class),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fun", TokenType::Fun),
+    ("if", TokenType::If),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("true", TokenType::True),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+];
+
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
 pub struct CodePosition {
     pub pos: usize,
@@ -121,6 +142,17 @@ impl CodePosition {
     }
 }
 
+/// Tells apart the sources compiled into one VM, so that spans from functions
+/// defined by an earlier source can be shown against it.
+pub type SourceId = usize;
+
+/// A stretch of source code, from `start` for `length` bytes.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Span {
+    pub start: CodePosition,
+    pub length: usize,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Token {
     pub typ: TokenType,
@@ -128,6 +160,15 @@ pub struct Token {
     pub length: usize,
 }
 
+impl Token {
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            length: self.length,
+        }
+    }
+}
+
 impl Display for Token {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(f, "{} at {}", self.typ, self.start)
@@ -137,7 +178,26 @@ impl Display for Token {
 #[derive(Clone, Debug, PartialEq)]
 pub struct ScanError {
     pub value: ScanErrorValue,
-    pub pos: CodePosition,
+    pub span: Span,
+}
+
+impl ScanError {
+    /// The error's `E00xx` code, as described on `Diagnostic::code`.
+    pub fn code(&self) -> &'static str {
+        match self.value {
+            ScanErrorValue::UnterminatedString(_) => "E0001",
+            ScanErrorValue::UnexpectedCharacter(_) => "E0002",
+        }
+    }
+}
+
+impl Display for ScanError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.value {
+            ScanErrorValue::UnterminatedString(_) => write!(f, "Unterminated string"),
+            ScanErrorValue::UnexpectedCharacter(c) => write!(f, "Unexpected character '{}'", c),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -160,6 +220,11 @@ impl<'s> Scanner<'s> {
         }
     }
 
+    /// Where scanning is up to; the end of the source once it's exhausted.
+    pub fn position(&self) -> CodePosition {
+        self.cursor
+    }
+
     pub fn substr(&self, pos: CodePosition, length: usize) -> String {
         self.src[pos.pos..pos.pos + length].to_string()
     }
@@ -262,6 +327,7 @@ impl<'s> Scanner<'s> {
     }
 
     fn scan_str(&mut self) -> ScanResult<Token> {
+        let open = self.cursor;
         self.cursor.inc_for('"');
 
         let token = self.scan_token(|c| c != &'"', |_| TokenType::String);
@@ -270,8 +336,12 @@ impl<'s> Scanner<'s> {
             self.cursor.inc_for('"');
             Ok(token)
         } else {
+            // Blame everything from the opening quote on.
             Err(ScanError {
-                pos: token.start,
+                span: Span {
+                    start: open,
+                    length: self.cursor.pos - open.pos,
+                },
                 value: ScanErrorValue::UnterminatedString(token),
             })
         }
@@ -280,24 +350,11 @@ impl<'s> Scanner<'s> {
     fn scan_identifier(&mut self) -> Token {
         self.scan_token(
             |c| c.is_alphanumeric(),
-            |l| match l.as_str() {
-                "and" => TokenType::And,
-                "class" => TokenType::Class,
-                "else" => TokenType::Else,
-                "false" => TokenType::False,
-                "for" => TokenType::For,
-                "fun" => TokenType::Fun,
-                "if" => TokenType::If,
-                "nil" => TokenType::Nil,
-                "or" => TokenType::Or,
-                "print" => TokenType::Print,
-                "return" => TokenType::Return,
-                "super" => TokenType::Super,
-                "this" => TokenType::This,
-                "true" => TokenType::True,
-                "var" => TokenType::Var,
-                "while" => TokenType::While,
-                _ => TokenType::Identifier,
+            |l| {
+                KEYWORDS
+                    .iter()
+                    .find(|(keyword, _)| *keyword == l)
+                    .map_or(TokenType::Identifier, |(_, typ)| *typ)
             },
         )
     }
@@ -336,8 +393,11 @@ impl<'s> Scanner<'s> {
                 } else if c.is_alphabetic() {
                     Some(Ok(self.scan_identifier()))
                 } else {
+                    let start = self.cursor;
+                    // Skip the character so scanning can resume after it.
+                    self.cursor.inc_for(c);
                     Some(Err(ScanError {
-                        pos: self.cursor,
+                        span: Span { start, length: c.len_utf8() },
                         value: ScanErrorValue::UnexpectedCharacter(c)
                     }))
                 }
@@ -1,47 +1,235 @@
+use std::collections::HashMap;
 use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::mem;
 
+use crate::chunk::Chunk;
+use crate::gc::Heap;
+use crate::scanner::SourceId;
+use crate::vm::RuntimeError;
 use broom::{
     prelude::{Trace, Tracer},
-    Handle, Heap,
+    Handle,
 };
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum TypeError {
     NotANumber(Value),
-    NotBoolLike(Value),
 }
 
 impl Display for TypeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::NotANumber(v) => write!(f, "{} is not a number", v),
-            Self::NotBoolLike(v) => write!(f, "{} cannot be coerced to a boolean", v),
         }
     }
 }
 
 pub type TypeResult<A> = Result<A, TypeError>;
 
-#[derive(Debug, Clone, PartialEq)]
+/// Where a closure captures a variable from when it is created: either a local
+/// of the enclosing function, or one of the enclosing function's own upvalues.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpvalueRef {
+    pub is_local: bool,
+    pub index: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct Function {
+    pub arity: u8,
+    pub chunk: Chunk,
+    /// `None` for the top-level script.
+    pub name: Option<String>,
+    pub upvalues: Vec<UpvalueRef>,
+    /// The source the function was compiled from, which its spans are in.
+    pub source: SourceId,
+}
+
+impl Function {
+    /// Disassemble the function's chunk, followed by those of the functions
+    /// declared within it.
+    pub fn disassemble(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.chunk
+            .disassemble(out, self.name.as_deref().unwrap_or("<script>"))?;
+        for constant in self.chunk.constants() {
+            if let Value::Obj(handle) = constant {
+                if let Object::Function(function) = unsafe { handle.get_unchecked() } {
+                    writeln!(out)?;
+                    function.disassemble(out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// A function along with the variables it has captured.
+#[derive(Debug)]
+pub struct Closure {
+    /// Always an `Object::Function`.
+    pub function: Handle<Object>,
+    /// Always `Object::Upvalue`s.
+    pub upvalues: Vec<Handle<Object>>,
+}
+
+/// A captured variable. It lives on the stack until it goes out of scope, at
+/// which point it is moved into the upvalue so closures can keep using it.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    /// Always `Object::Closure`s.
+    pub methods: HashMap<String, Handle<Object>>,
+}
+
+impl Class {
+    /// The method run against new instances when the class is called.
+    pub const INITIALIZER: &'static str = "init";
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    /// Always an `Object::Class`.
+    pub class: Handle<Object>,
+    pub fields: HashMap<String, Value>,
+}
+
+/// A method read off an instance, remembering the instance it will be run against.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    /// Always an `Object::Closure`.
+    pub method: Handle<Object>,
+}
+
+/// A function implemented in Rust. It's handed the heap, for allocating its
+/// result, and its arguments.
+pub type NativeFn = dyn Fn(&mut Heap, &[Value]) -> Result<Value, RuntimeError>;
+
+pub struct Native {
+    pub name: String,
+    pub arity: u8,
+    pub function: Box<NativeFn>,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Native")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
 #[repr(u8)]
 pub enum Object {
     Str(String),
+    Function(Function),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+}
+
+impl Object {
+    /// Roughly how many bytes the object takes up, counting what it owns but
+    /// not other objects it refers to.
+    pub fn size(&self) -> usize {
+        let owned = match self {
+            Object::Str(s) => s.capacity(),
+            Object::Function(function) => {
+                function.chunk.size()
+                    + function.name.as_ref().map_or(0, String::capacity)
+                    + function.upvalues.capacity() * mem::size_of::<UpvalueRef>()
+            }
+            Object::Closure(closure) => {
+                closure.upvalues.capacity() * mem::size_of::<Handle<Object>>()
+            }
+            Object::Upvalue(_) | Object::BoundMethod(_) => 0,
+            Object::Class(class) => {
+                class.name.capacity()
+                    + class.methods.capacity() * mem::size_of::<(String, Handle<Object>)>()
+            }
+            Object::Instance(instance) => {
+                instance.fields.capacity() * mem::size_of::<(String, Value)>()
+            }
+            Object::Native(native) => native.name.capacity(),
+        };
+        mem::size_of::<Object>() + owned
+    }
+
+    /// Call `each` with every object this one refers to, and so keeps alive.
+    pub fn children(&self, mut each: impl FnMut(Handle<Object>)) {
+        match self {
+            Object::Str(_) | Object::Native(_) | Object::Upvalue(Upvalue::Open(_)) => {}
+            Object::Function(function) => function
+                .chunk
+                .constants()
+                .iter()
+                .filter_map(|value| value.as_object())
+                .for_each(each),
+            Object::Closure(closure) => {
+                each(closure.function);
+                closure.upvalues.iter().copied().for_each(each);
+            }
+            Object::Upvalue(Upvalue::Closed(value)) => value.as_object().into_iter().for_each(each),
+            Object::Class(class) => class.methods.values().copied().for_each(each),
+            Object::Instance(instance) => {
+                each(instance.class);
+                instance
+                    .fields
+                    .values()
+                    .filter_map(|value| value.as_object())
+                    .for_each(each);
+            }
+            Object::BoundMethod(bound) => {
+                each(bound.method);
+                bound.receiver.as_object().into_iter().for_each(each);
+            }
+        }
+    }
 }
 
 impl Display for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Str(s) => write!(f, "\"{}\"", s),
+            Self::Function(function) => function.fmt(f),
+            Self::Closure(closure) => unsafe { closure.function.get_unchecked().fmt(f) },
+            Self::Upvalue(_) => write!(f, "<upvalue>"),
+            Self::Class(class) => write!(f, "{}", class.name),
+            Self::Instance(instance) => unsafe {
+                write!(f, "{} instance", instance.class.get_unchecked())
+            },
+            Self::BoundMethod(bound) => unsafe { bound.method.get_unchecked().fmt(f) },
+            Self::Native(native) => write!(f, "<native fn {}>", native.name),
         }
     }
 }
 
+// Marking is done by `gc::Heap` with `Object::children`, so broom never needs
+// to trace from an object itself. Doing so would recurse once per link in the
+// object graph, and overflow the native stack on long chains of objects.
 impl Trace<Self> for Object {
-    fn trace(&self, _tracer: &mut Tracer<Self>) {
-        match self {
-            Object::Str(_) => {}
-        }
-    }
+    fn trace(&self, _tracer: &mut Tracer<Self>) {}
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -64,10 +252,47 @@ impl Display for Value {
     }
 }
 
+impl Value {
+    /// The object the value refers to, if any.
+    pub fn as_object(self) -> Option<Handle<Object>> {
+        match self {
+            Self::Obj(handle) => Some(handle),
+            _ => None,
+        }
+    }
+
+    /// Render the value for a programmer rather than for output: strings are
+    /// escaped and instances show their fields. Values nested inside are
+    /// shown with `Display`, so cycles can't recurse forever.
+    pub fn repr(&self) -> String {
+        match self {
+            Self::Obj(handle) => match unsafe { handle.get_unchecked() } {
+                Object::Str(s) => format!("{:?}", s),
+                Object::Instance(instance) => {
+                    let mut fields: Vec<_> = instance.fields.iter().collect();
+                    fields.sort_by_key(|(name, _)| *name);
+                    let fields: Vec<String> = fields
+                        .into_iter()
+                        .map(|(name, value)| format!("{}: {}", name, value))
+                        .collect();
+                    let class = unsafe { instance.class.get_unchecked() };
+                    if fields.is_empty() {
+                        format!("{} {{}}", class)
+                    } else {
+                        format!("{} {{ {} }}", class, fields.join(", "))
+                    }
+                }
+                object => object.to_string(),
+            },
+            value => value.to_string(),
+        }
+    }
+}
+
 #[allow(dead_code)]
 impl Value {
     #[inline]
-    pub fn negate(_heap: &mut Heap<Object>, val: Value) -> TypeResult<Value> {
+    pub fn negate(_heap: &mut Heap, val: Value) -> TypeResult<Value> {
         match val {
             Self::Double(double) => Ok(Self::Double(-double)),
             _ => Err(TypeError::NotANumber(val)),
@@ -75,16 +300,19 @@ impl Value {
     }
 
     #[inline]
-    pub fn not(_heap: &mut Heap<Object>, val: Value) -> TypeResult<Value> {
-        match val {
-            Self::Bool(bool) => Ok(Value::Bool(!bool)),
-            Self::Nil => Ok(Value::Bool(true)),
-            _ => Err(TypeError::NotBoolLike(val)),
-        }
+    /// Whether a value counts as true in a condition. Everything except `nil`
+    /// and `false` does.
+    pub fn truthy(self) -> bool {
+        !matches!(self, Self::Nil | Self::Bool(false))
     }
 
     #[inline]
-    pub fn add(heap: &mut Heap<Object>, a: Value, b: Value) -> TypeResult<Value> {
+    pub fn not(_heap: &mut Heap, val: Value) -> TypeResult<Value> {
+        Ok(Value::Bool(!val.truthy()))
+    }
+
+    #[inline]
+    pub fn add(heap: &mut Heap, a: Value, b: Value) -> TypeResult<Value> {
         match (a, b) {
             (Self::Double(a), Self::Double(b)) => Ok(Self::Double(a + b)),
             (Self::Obj(a), Self::Obj(b)) => unsafe {
@@ -94,6 +322,7 @@ impl Value {
                         let obj = heap.insert_temp(str);
                         Ok(Self::Obj(obj))
                     }
+                    _ => Err(TypeError::NotANumber(Self::Obj(a))),
                 }
             },
             vw => Err(TypeError::NotANumber(vw.0)),
@@ -101,7 +330,7 @@ impl Value {
     }
 
     #[inline]
-    pub fn subtract(_heap: &mut Heap<Object>, a: Value, b: Value) -> TypeResult<Value> {
+    pub fn subtract(_heap: &mut Heap, a: Value, b: Value) -> TypeResult<Value> {
         match (a, b) {
             (Self::Double(a), Self::Double(b)) => Ok(Self::Double(a - b)),
             vw => Err(TypeError::NotANumber(vw.0)),
@@ -109,7 +338,7 @@ impl Value {
     }
 
     #[inline]
-    pub fn multiply(_heap: &mut Heap<Object>, a: Value, b: Value) -> TypeResult<Value> {
+    pub fn multiply(_heap: &mut Heap, a: Value, b: Value) -> TypeResult<Value> {
         match (a, b) {
             (Self::Double(a), Self::Double(b)) => Ok(Self::Double(a * b)),
             vw => Err(TypeError::NotANumber(vw.0)),
@@ -117,7 +346,7 @@ impl Value {
     }
 
     #[inline]
-    pub fn divide(_heap: &mut Heap<Object>, a: Value, b: Value) -> TypeResult<Value> {
+    pub fn divide(_heap: &mut Heap, a: Value, b: Value) -> TypeResult<Value> {
         match (a, b) {
             (Self::Double(a), Self::Double(b)) => Ok(Self::Double(a / b)),
             vw => Err(TypeError::NotANumber(vw.0)),
@@ -125,7 +354,7 @@ impl Value {
     }
 
     #[inline]
-    pub fn divide_mut(_heap: &mut Heap<Object>, a: &mut Value, b: Value) -> TypeResult<()> {
+    pub fn divide_mut(_heap: &mut Heap, a: &mut Value, b: Value) -> TypeResult<()> {
         match (a, b) {
             (Self::Double(a), Self::Double(b)) => {
                 *a /= b;
@@ -136,17 +365,21 @@ impl Value {
     }
 
     #[inline]
-    pub fn equal(_heap: &mut Heap<Object>, a: Value, b: Value) -> TypeResult<Value> {
+    pub fn equal(_heap: &mut Heap, a: Value, b: Value) -> TypeResult<Value> {
         match (a, b) {
+            // Strings are compared by value, everything else by identity.
             (Self::Obj(a), Self::Obj(b)) => unsafe {
-                Ok(Value::Bool(a.get_unchecked().eq(b.get_unchecked())))
+                match (a.get_unchecked(), b.get_unchecked()) {
+                    (Object::Str(a), Object::Str(b)) => Ok(Value::Bool(a == b)),
+                    _ => Ok(Value::Bool(a == b)),
+                }
             },
             _ => Ok(Value::Bool(a == b)),
         }
     }
 
     #[inline]
-    pub fn greater(_heap: &mut Heap<Object>, a: Value, b: Value) -> TypeResult<Value> {
+    pub fn greater(_heap: &mut Heap, a: Value, b: Value) -> TypeResult<Value> {
         match (a, b) {
             (Self::Double(a), Self::Double(b)) => Ok(Value::Bool(a > b)),
             vw => Err(TypeError::NotANumber(vw.0)),
@@ -154,7 +387,7 @@ impl Value {
     }
 
     #[inline]
-    pub fn less(_heap: &mut Heap<Object>, a: Value, b: Value) -> TypeResult<Value> {
+    pub fn less(_heap: &mut Heap, a: Value, b: Value) -> TypeResult<Value> {
         match (a, b) {
             (Self::Double(a), Self::Double(b)) => Ok(Value::Bool(a < b)),
             vw => Err(TypeError::NotANumber(vw.0)),
@@ -1,19 +1,57 @@
+use std::collections::HashMap;
+use std::env;
 use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::time::Instant;
+use std::{mem, ptr};
 
-use broom::Heap;
+use broom::Handle;
 
 use crate::compiler::Compiler;
-use crate::value::Object;
+use crate::data::u24;
+use crate::gc::{Heap, HeapStats};
+use crate::scanner::{SourceId, Span};
 use crate::value::TypeError;
 use crate::value::TypeResult;
+use crate::value::{BoundMethod, Class, Closure, Function, Instance, Native, Object, Upvalue};
 use crate::{compiler::CompileError, op::Op};
 
 use crate::{chunk::Chunk, value::Value};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RuntimeError {
     StackUnderflow,
     Type(TypeError),
+    UndefinedVariable(String),
+    NotCallable(Value),
+    ArityMismatch {
+        expected: u8,
+        actual: u8,
+    },
+    StackOverflow,
+    NotAnInstance(Value),
+    UndefinedProperty(String),
+    SuperclassNotClass(Value),
+    /// Printing failed.
+    Output(io::ErrorKind),
+}
+
+impl RuntimeError {
+    /// The error's `E02xx` code, as described on `Diagnostic::code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RuntimeError::StackUnderflow => "E0200",
+            RuntimeError::Type(TypeError::NotANumber(_)) => "E0201",
+            RuntimeError::UndefinedVariable(_) => "E0203",
+            RuntimeError::NotCallable(_) => "E0204",
+            RuntimeError::ArityMismatch { .. } => "E0205",
+            RuntimeError::StackOverflow => "E0206",
+            RuntimeError::NotAnInstance(_) => "E0207",
+            RuntimeError::UndefinedProperty(_) => "E0208",
+            RuntimeError::SuperclassNotClass(_) => "E0209",
+            RuntimeError::Output(_) => "E0210",
+        }
+    }
 }
 
 impl Display for RuntimeError {
@@ -21,6 +59,20 @@ impl Display for RuntimeError {
         match self {
             RuntimeError::StackUnderflow => write!(f, "StackUnderflow"),
             RuntimeError::Type(e) => write!(f, "TypeError: {}", e),
+            RuntimeError::UndefinedVariable(name) => write!(f, "Undefined variable '{}'", name),
+            RuntimeError::NotCallable(value) => write!(f, "{} is not callable", value),
+            RuntimeError::ArityMismatch { expected, actual } => {
+                write!(f, "Expected {} arguments but got {}", expected, actual)
+            }
+            RuntimeError::StackOverflow => write!(f, "StackOverflow"),
+            RuntimeError::NotAnInstance(value) => {
+                write!(f, "{} is not an instance, so has no properties", value)
+            }
+            RuntimeError::UndefinedProperty(name) => write!(f, "Undefined property '{}'", name),
+            RuntimeError::SuperclassNotClass(value) => {
+                write!(f, "{} is not a class, so can't be inherited from", value)
+            }
+            RuntimeError::Output(kind) => write!(f, "Couldn't write output: {}", kind),
         }
     }
 }
@@ -31,49 +83,503 @@ impl From<TypeError> for RuntimeError {
     }
 }
 
+impl From<io::Error> for RuntimeError {
+    fn from(e: io::Error) -> Self {
+        Self::Output(e.kind())
+    }
+}
+
+/// A call in progress when a runtime error occurred.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// `None` for the top-level script.
+    pub function: Option<String>,
+    /// The source of the instruction executing in the frame: the failing one
+    /// for the innermost frame, and a call for the rest.
+    pub span: Span,
+    /// The source `span` is in.
+    pub source: SourceId,
+    /// How many identical frames directly outside this one were merged into
+    /// it, so unbounded recursion doesn't bury the rest of the trace.
+    pub repeats: usize,
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        // Lines count from zero internally.
+        write!(f, "[line {}] in ", self.span.start.line + 1)?;
+        match &self.function {
+            Some(name) => write!(f, "{}()", name)?,
+            None => write!(f, "script")?,
+        }
+        if self.repeats > 0 {
+            write!(f, " (repeated {} more times)", self.repeats)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[allow(dead_code)]
 pub enum InterpretError {
-    Compile(CompileError),
-    Runtime(RuntimeError),
+    /// Every error found, in source order.
+    Compile(Vec<CompileError>),
+    Runtime {
+        error: RuntimeError,
+        /// The source of the failing instruction.
+        span: Span,
+        /// The source `span` is in, which may be from before the one which
+        /// was being run.
+        source: SourceId,
+        /// Innermost frame first.
+        trace: Vec<TraceFrame>,
+    },
+}
+
+/// The maximum call depth, beyond which a script is assumed to be recursing
+/// without bound.
+const FRAMES_MAX: usize = 256;
+
+/// A function invocation in progress.
+#[derive(Clone, Copy)]
+struct CallFrame {
+    /// Always an `Object::Closure`.
+    closure: Handle<Object>,
+    /// Where to resume execution. Only synced with `run`'s own instruction
+    /// pointer when control leaves the frame.
+    ip: *const u8,
+    /// The index of the frame's slot zero on the stack.
+    slots: usize,
+}
+
+impl CallFrame {
+    #[inline]
+    /// The frame's closure. The lifetime is unbounded, as closures outlive any
+    /// frame executing them.
+    unsafe fn closure<'a>(&self) -> &'a Closure {
+        match &*(self.closure.get_unchecked() as *const Object) {
+            Object::Closure(closure) => closure,
+            _ => panic!("Call frame without a closure"),
+        }
+    }
+
+    #[inline]
+    /// The chunk of the frame's function, with the same unbounded lifetime.
+    unsafe fn chunk<'a>(&self) -> &'a Chunk {
+        &closure_function(self.closure).chunk
+    }
+
+    /// Describe the frame for a stack trace. `ip` has always moved past the
+    /// instruction being executed, so it's the previous byte's span we want.
+    unsafe fn trace(&self) -> TraceFrame {
+        let chunk = self.chunk();
+        let pos = (self.ip as usize) - (chunk.code_ptr() as usize);
+        let function = closure_function(self.closure);
+        TraceFrame {
+            function: function.name.clone(),
+            span: chunk.get_span(pos.saturating_sub(1)),
+            source: function.source,
+            repeats: 0,
+        }
+    }
+}
+
+#[inline]
+/// The function wrapped by a closure. The lifetime is unbounded, as functions
+/// outlive the closures wrapping them.
+unsafe fn closure_function<'a>(closure: Handle<Object>) -> &'a Function {
+    match &*(closure.get_unchecked() as *const Object) {
+        Object::Closure(closure) => match &*(closure.function.get_unchecked() as *const Object) {
+            Object::Function(function) => function,
+            _ => panic!("Closure without a function"),
+        },
+        _ => panic!("Expected a closure"),
+    }
 }
 
 pub struct VM {
-    chunk: Chunk,
-    heap: Heap<Object>,
+    heap: Heap,
     stack: Stack,
+    frames: Vec<CallFrame>,
+    globals: HashMap<String, Value>,
+    /// Upvalues still pointing into the stack, and so not yet `Upvalue::Closed`.
+    open_upvalues: Vec<Handle<Object>>,
+    /// The function compiled from the most recent source, kept for inspection.
+    last_script: Option<Handle<Object>>,
+    /// Where scripts print to.
+    out: Box<dyn Write>,
+    tracing: Tracing,
+    /// Where tracing goes, if not to `out`.
+    trace_sink: Option<Box<dyn Write>>,
+    /// The id the next source compiled gets.
+    next_source: SourceId,
 }
 
+/// What the VM reports as it goes, for debugging it or the scripts it runs.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Tracing {
+    /// Disassemble each function once it's compiled.
+    pub print_code: bool,
+    /// Show the stack, and disassemble each instruction, as it's executed.
+    pub trace_exec: bool,
+    /// Report how much each garbage collection freed, and how long it took.
+    pub gc_log: bool,
+}
+
+/// Setting this environment variable to anything turns on GC stress mode.
+const GC_STRESS_VAR: &str = "DROOL_GC_STRESS";
+
 pub type InterpretResult<'s, A> = Result<A, InterpretError>;
 
 pub type RunResult<A> = Result<A, RuntimeError>;
 
 impl VM {
-    pub fn new(chunk: Chunk) -> VM {
-        VM {
-            chunk,
+    pub fn new() -> VM {
+        let mut vm = VM {
             stack: Stack::default(),
             heap: Heap::new(),
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            last_script: None,
+            out: Box::new(io::stdout()),
+            tracing: Tracing::default(),
+            trace_sink: None,
+            next_source: 0,
+        };
+        vm.heap.set_stress(env::var_os(GC_STRESS_VAR).is_some());
+
+        let start = Instant::now();
+        vm.define_native("clock", 0, move |_, _| {
+            Ok(Value::Double(start.elapsed().as_secs_f64()))
+        });
+        vm.define_native("gcStats", 0, |heap, _| {
+            let fields = heap
+                .stats()
+                .fields()
+                .iter()
+                .map(|(name, n)| (name.to_string(), Value::Double(*n as f64)))
+                .collect();
+            let class = heap.insert_temp(Object::Class(Class {
+                name: "GcStats".to_string(),
+                methods: HashMap::new(),
+            }));
+            let stats = heap.insert_temp(Object::Instance(Instance { class, fields }));
+            Ok(Value::Obj(stats))
+        });
+        vm
+    }
+
+    /// Collect garbage at the first chance after every allocation, rather than
+    /// only once enough has built up. Slow, but quick to show up objects which
+    /// are in use without being reachable from any root.
+    pub fn with_gc_stress(mut self, stress: bool) -> VM {
+        self.heap.set_stress(stress);
+        self
+    }
+
+    /// Print to `out` rather than stdout.
+    #[cfg(test)]
+    pub fn with_output(self, out: Box<dyn Write>) -> VM {
+        VM { out, ..self }
+    }
+
+    /// Report whatever `tracing` asks for, to `sink` if there is one and
+    /// otherwise along with everything else printed.
+    pub fn with_tracing(self, tracing: Tracing, sink: Option<Box<dyn Write>>) -> VM {
+        VM {
+            tracing,
+            trace_sink: sink,
+            ..self
         }
     }
 
+    /// Forget everything defined so far, keeping only how the VM was set up.
+    pub fn reset(&mut self) {
+        let out = mem::replace(&mut self.out, Box::new(io::sink()));
+        let stress = self.heap.stress();
+        *self = VM {
+            out,
+            tracing: self.tracing,
+            trace_sink: self.trace_sink.take(),
+            ..VM::new().with_gc_stress(stress)
+        };
+    }
+
+    /// Expose a Rust function to scripts as a global, replacing any global of
+    /// the same name.
+    pub fn define_native<F>(&mut self, name: &str, arity: u8, function: F)
+    where
+        F: Fn(&mut Heap, &[Value]) -> RunResult<Value> + 'static,
+    {
+        let native = self.heap.insert_temp(Object::Native(Native {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        }));
+        self.globals.insert(name.to_string(), Value::Obj(native));
+    }
+
+    /// Expose command line arguments to scripts, as `argCount()` and
+    /// `arg(n)`. Asking for an argument which isn't there produces `nil`.
+    pub fn define_args(&mut self, args: Vec<String>) {
+        let count = args.len() as f64;
+        self.define_native("argCount", 0, move |_, _| Ok(Value::Double(count)));
+        self.define_native("arg", 1, move |heap, params| match params[0] {
+            Value::Double(n) => Ok(args
+                .get(n as usize)
+                .filter(|_| n >= 0.0 && n.fract() == 0.0)
+                .map_or(Value::Nil, |arg| {
+                    Value::Obj(heap.insert_temp(Object::Str(arg.clone())))
+                })),
+            other => Err(RuntimeError::Type(TypeError::NotANumber(other))),
+        });
+    }
+
     pub fn interpret<'s>(&mut self, src: &'s str) -> InterpretResult<'s, ()> {
-        let (chunk, heap) = Compiler::compile(src).map_err(InterpretError::Compile)?;
+        let script = self.compile_script(src, false)?;
+        self.run_script(script).map(|_| ())
+    }
 
-        self.chunk = chunk;
-        self.heap = heap;
-        self.run().map_err(InterpretError::Runtime)
+    /// Compile `src` without running it, leaving it as the `last_script`.
+    pub fn compile<'s>(&mut self, src: &'s str) -> InterpretResult<'s, &Function> {
+        let script = self.compile_script(src, false)?;
+        self.last_script = Some(script);
+        Ok(self.last_script().expect("Script was just compiled"))
+    }
+
+    /// Run a line of REPL input, producing the value of its trailing expression
+    /// statement if it has one, or `nil` otherwise.
+    pub fn evaluate<'s>(&mut self, src: &'s str) -> InterpretResult<'s, Value> {
+        let script = self.compile_script(src, true)?;
+        self.run_script(script)
+    }
+
+    fn compile_script<'s>(
+        &mut self,
+        src: &'s str,
+        repl: bool,
+    ) -> InterpretResult<'s, Handle<Object>> {
+        let code_sink: Option<&mut dyn Write> = if self.tracing.print_code {
+            let out: &mut dyn Write = self.trace_sink.as_deref_mut().unwrap_or(&mut *self.out);
+            Some(out)
+        } else {
+            None
+        };
+        let source = self.next_source;
+        self.next_source += 1;
+        let result = if repl {
+            Compiler::compile_repl(src, source, &mut self.heap, code_sink)
+        } else {
+            Compiler::compile(src, source, &mut self.heap, code_sink)
+        };
+        result.map_err(InterpretError::Compile)
+    }
+
+    /// The function compiled from the most recent source run.
+    pub fn last_script(&self) -> Option<&Function> {
+        self.last_script.map(|script| {
+            match unsafe { &*(script.get_unchecked() as *const Object) } {
+                Object::Function(function) => function,
+                _ => panic!("Expected a function"),
+            }
+        })
+    }
+
+    /// The id the next source compiled will get, which runtime errors from
+    /// its functions will carry.
+    pub fn next_source(&self) -> SourceId {
+        self.next_source
+    }
+
+    /// The stack, which is empty after a successful run, but as it was at the
+    /// point of failure after a runtime error.
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    /// What's allocated, which scripts can also find out with `gcStats()`.
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// The names of every global defined so far, natives included.
+    pub fn global_names(&self) -> impl Iterator<Item = &str> {
+        self.globals.keys().map(String::as_str)
+    }
+
+    fn run_script<'s>(&mut self, script: Handle<Object>) -> InterpretResult<'s, Value> {
+        self.last_script = Some(script);
+        // Whatever a failed run left behind was only kept for inspection.
+        self.stack = Stack::default();
+        let script = self.heap.insert_temp(Object::Closure(Closure {
+            function: script,
+            upvalues: Vec::new(),
+        }));
+        self.stack.push(Value::Obj(script));
+        let result = self
+            .call_value(Value::Obj(script), 0)
+            .and_then(|()| self.run());
+        result.map_err(|error| {
+            let mut trace: Vec<TraceFrame> = Vec::new();
+            for frame in self.frames.iter().rev() {
+                let frame = unsafe { frame.trace() };
+                match trace.last_mut() {
+                    Some(last)
+                        if last.function == frame.function
+                            && last.span == frame.span
+                            && last.source == frame.source =>
+                    {
+                        last.repeats += 1
+                    }
+                    _ => trace.push(frame),
+                }
+            }
+            let (span, source) = trace.first().map_or_else(
+                || (Span::default(), unsafe { closure_function(script) }.source),
+                |frame| (frame.span, frame.source),
+            );
+            // Closures which escaped into globals may still point into the
+            // stack. Later calls to `interpret` can still reach them, so they
+            // must keep their variables once it's cleared. Until then, the
+            // stack stays as the error left it, for inspection.
+            let _ = self.close_upvalues(0);
+            self.open_upvalues.clear();
+            self.frames.clear();
+            InterpretError::Runtime {
+                error,
+                span,
+                source,
+                trace,
+            }
+        })
     }
 
     #[inline]
-    fn eff(&mut self, op: fn(&mut Heap<Object>, Value) -> TypeResult<()>) -> RunResult<()> {
-        let val = self.stack.pop()?;
-        op(&mut self.heap, val)?;
+    /// Resolve the name referenced by a global variable instruction.
+    fn read_name(chunk: &Chunk, index: u24) -> &str {
+        match chunk.get_constant(index.into()) {
+            Value::Obj(handle) => match unsafe { handle.get_unchecked() } {
+                Object::Str(name) => name,
+                _ => panic!("Corrupt bytecode"),
+            },
+            _ => panic!("Corrupt bytecode"),
+        }
+    }
+
+    /// Push a frame for a call to `callee`, whose arguments are already on the stack.
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> RunResult<()> {
+        if let Value::Obj(handle) = callee {
+            match unsafe { handle.get_unchecked() } {
+                Object::Closure(_) => return self.call(handle, arg_count),
+                Object::Native(native) => {
+                    if arg_count != native.arity {
+                        return Err(RuntimeError::ArityMismatch {
+                            expected: native.arity,
+                            actual: arg_count,
+                        });
+                    }
+                    let args = self.stack.top(arg_count.into())?;
+                    let result = (native.function)(&mut self.heap, args)?;
+                    // Drop the arguments and the native itself.
+                    self.stack
+                        .truncate(self.stack.len() - usize::from(arg_count) - 1);
+                    self.stack.push(result);
+                    return Ok(());
+                }
+                Object::BoundMethod(bound) => {
+                    // The receiver takes the callee's slot, where methods expect `this`.
+                    self.stack.set_from_top(arg_count.into(), bound.receiver)?;
+                    return self.call(bound.method, arg_count);
+                }
+                Object::Class(class) => {
+                    let instance = self.heap.insert_temp(Object::Instance(Instance {
+                        class: handle,
+                        fields: HashMap::new(),
+                    }));
+                    self.stack
+                        .set_from_top(arg_count.into(), Value::Obj(instance))?;
+                    return match class.methods.get(Class::INITIALIZER) {
+                        Some(initializer) => self.call(*initializer, arg_count),
+                        None if arg_count == 0 => Ok(()),
+                        None => Err(RuntimeError::ArityMismatch {
+                            expected: 0,
+                            actual: arg_count,
+                        }),
+                    };
+                }
+                _ => {}
+            }
+        }
+        Err(RuntimeError::NotCallable(callee))
+    }
+
+    /// Call a method on the instance beneath the arguments on the stack, without
+    /// creating a bound method if possible.
+    fn invoke(&mut self, name: &str, arg_count: u8) -> RunResult<()> {
+        let receiver = *self.stack.peek_at(arg_count.into())?;
+        let instance = unsafe { VM::as_instance(receiver)? };
+
+        // A field holding a callable shadows any method of the same name.
+        if let Some(field) = instance.fields.get(name) {
+            let field = *field;
+            self.stack.set_from_top(arg_count.into(), field)?;
+            return self.call_value(field, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    /// Call the named method of `class` on the receiver beneath the arguments.
+    fn invoke_from_class(
+        &mut self,
+        class: Handle<Object>,
+        name: &str,
+        arg_count: u8,
+    ) -> RunResult<()> {
+        match unsafe { VM::as_class(class) }.methods.get(name) {
+            Some(method) => self.call(*method, arg_count),
+            None => Err(RuntimeError::UndefinedProperty(name.to_string())),
+        }
+    }
+
+    /// Bind the named method of `class` to the receiver on top of the stack.
+    fn bind_method(&mut self, class: Handle<Object>, name: &str) -> RunResult<Value> {
+        let method = match unsafe { VM::as_class(class) }.methods.get(name) {
+            Some(method) => *method,
+            None => return Err(RuntimeError::UndefinedProperty(name.to_string())),
+        };
+        let receiver = *self.stack.peek()?;
+        let bound = self
+            .heap
+            .insert_temp(Object::BoundMethod(BoundMethod { receiver, method }));
+        Ok(Value::Obj(bound))
+    }
+
+    fn call(&mut self, closure: Handle<Object>, arg_count: u8) -> RunResult<()> {
+        let arity = unsafe { closure_function(closure) }.arity;
+        if arg_count != arity {
+            return Err(RuntimeError::ArityMismatch {
+                expected: arity,
+                actual: arg_count,
+            });
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(RuntimeError::StackOverflow);
+        }
+
+        let mut frame = CallFrame {
+            closure,
+            ip: ptr::null(),
+            slots: self.stack.len() - usize::from(arg_count) - 1,
+        };
+        frame.ip = unsafe { frame.chunk() }.code_ptr();
+        self.frames.push(frame);
         Ok(())
     }
 
     #[inline]
-    fn op_unary(&mut self, op: fn(&mut Heap<Object>, Value) -> TypeResult<Value>) -> RunResult<()> {
+    fn op_unary(&mut self, op: fn(&mut Heap, Value) -> TypeResult<Value>) -> RunResult<()> {
         let top = self.stack.peek()?;
         let res = op(&mut self.heap, *top)?;
         self.stack.pop()?;
@@ -82,10 +588,7 @@ impl VM {
     }
 
     #[inline]
-    fn op_binary(
-        &mut self,
-        op: fn(&mut Heap<Object>, Value, Value) -> TypeResult<Value>,
-    ) -> RunResult<()> {
+    fn op_binary(&mut self, op: fn(&mut Heap, Value, Value) -> TypeResult<Value>) -> RunResult<()> {
         let b = self.stack.pop()?;
         let a = self.stack.pop()?;
         let res = op(&mut self.heap, a, b).map_err(TypeError::into);
@@ -103,68 +606,422 @@ impl VM {
         }
     }
 
-    pub fn run(&mut self) -> RunResult<()> {
-        let mut ip = self.chunk.code_ptr();
-        let mut op_index: usize = 0;
+    /// Find or create the upvalue for a stack slot, so that closures capturing
+    /// the same variable share it.
+    fn capture_upvalue(&mut self, slot: usize) -> Handle<Object> {
+        let existing = self.open_upvalues.iter().find(|upvalue| {
+            matches!(
+                unsafe { upvalue.get_unchecked() },
+                Object::Upvalue(Upvalue::Open(open)) if *open == slot
+            )
+        });
+        if let Some(upvalue) = existing {
+            return *upvalue;
+        }
 
-        unsafe {
-            loop {
-                let op: Op;
-                if cfg!(debug_assertions) {
-                    if !self.stack.is_empty() {
-                        println!("{}", self.stack);
-                    }
+        let upvalue = self.heap.insert_temp(Object::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
 
-                    let pos = (ip as usize) - (self.chunk.code_ptr() as usize);
-                    op = Op::read_and_advance(&mut ip);
-                    op.print(&self.chunk, op_index, pos);
-                    op_index += 1;
-                } else {
-                    op = Op::read_and_advance(&mut ip);
+    /// Move every variable at or above stack slot `from` into its upvalue.
+    fn close_upvalues(&mut self, from: usize) -> RunResult<()> {
+        let stack = &self.stack;
+        let mut result = Ok(());
+        self.open_upvalues.retain(|handle| {
+            let upvalue = unsafe { handle.get_mut_unchecked() };
+            match upvalue {
+                Object::Upvalue(Upvalue::Open(slot)) if *slot >= from => {
+                    match stack.get(*slot) {
+                        Ok(value) => *upvalue = Object::Upvalue(Upvalue::Closed(value)),
+                        Err(e) => result = Err(e),
+                    }
+                    false
                 }
+                _ => true,
+            }
+        });
+        result
+    }
 
-                match op {
-                    Op::Return => {
-                        return Ok(());
+    #[inline]
+    fn read_upvalue(&self, upvalue: Handle<Object>) -> RunResult<Value> {
+        match unsafe { upvalue.get_unchecked() } {
+            Object::Upvalue(Upvalue::Open(slot)) => self.stack.get(*slot),
+            Object::Upvalue(Upvalue::Closed(value)) => Ok(*value),
+            _ => panic!("Closure captured a non-upvalue"),
+        }
+    }
+
+    #[inline]
+    fn write_upvalue(&mut self, upvalue: Handle<Object>, value: Value) -> RunResult<()> {
+        match unsafe { upvalue.get_mut_unchecked() } {
+            Object::Upvalue(Upvalue::Open(slot)) => self.stack.set(*slot, value),
+            Object::Upvalue(Upvalue::Closed(closed)) => {
+                *closed = value;
+                Ok(())
+            }
+            _ => panic!("Closure captured a non-upvalue"),
+        }
+    }
+
+    #[inline]
+    /// The lifetime is unbounded; the caller must not hold on to the instance
+    /// past the point where it might be collected.
+    unsafe fn as_instance<'a>(value: Value) -> RunResult<&'a mut Instance> {
+        if let Value::Obj(handle) = value {
+            if let Object::Instance(instance) = &mut *(handle.get_mut_unchecked() as *mut Object) {
+                return Ok(instance);
+            }
+        }
+        Err(RuntimeError::NotAnInstance(value))
+    }
+
+    #[inline]
+    /// As with `as_instance`, but for handles which must refer to a class.
+    unsafe fn as_class<'a>(handle: Handle<Object>) -> &'a mut Class {
+        match &mut *(handle.get_mut_unchecked() as *mut Object) {
+            Object::Class(class) => class,
+            _ => panic!("Expected a class"),
+        }
+    }
+
+    #[inline]
+    /// Change the object behind `handle` in place, counting however much it
+    /// grows against the heap.
+    unsafe fn grow(&mut self, handle: Handle<Object>, change: impl FnOnce()) {
+        let before = handle.get_unchecked().size();
+        change();
+        self.heap
+            .grew(handle.get_unchecked().size().saturating_sub(before));
+    }
+
+    /// Run from the innermost call frame until it returns, producing its return
+    /// value.
+    pub fn run(&mut self) -> RunResult<Value> {
+        let mut ip = self.frames.last().ok_or(RuntimeError::StackUnderflow)?.ip;
+        let result = unsafe { self.execute(&mut ip) };
+        if result.is_err() {
+            // Frames only record their ip on calls, so the innermost one would
+            // otherwise report wherever it last called from.
+            if let Some(frame) = self.frames.last_mut() {
+                frame.ip = ip;
+            }
+        }
+        result
+    }
+
+    /// Free every object which the program can no longer reach.
+    fn collect_garbage(&mut self) -> io::Result<()> {
+        let (bytes, objects) = (self.heap.bytes(), self.heap.len());
+        let start = Instant::now();
+        let roots = self
+            .stack
+            .0
+            .iter()
+            .chain(self.globals.values())
+            .filter_map(|value| value.as_object())
+            .chain(self.frames.iter().map(|frame| frame.closure))
+            .chain(self.open_upvalues.iter().copied())
+            .chain(self.last_script);
+        self.heap.collect(roots);
+
+        if self.tracing.gc_log {
+            let out = self.trace_sink.as_deref_mut().unwrap_or(&mut *self.out);
+            writeln!(
+                out,
+                "-- gc: {} -> {} bytes, {} -> {} objects, {:?}",
+                bytes,
+                self.heap.bytes(),
+                objects,
+                self.heap.len(),
+                start.elapsed()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write out the stack, and the instruction at `pos` which is about to run.
+    fn trace(&mut self, chunk: &Chunk, op: &Op, pos: usize) -> io::Result<()> {
+        let out = self.trace_sink.as_deref_mut().unwrap_or(&mut *self.out);
+        if !self.stack.is_empty() {
+            writeln!(out, "{}", self.stack)?;
+        }
+        op.print(out, chunk, pos)
+    }
+
+    /// The dispatch loop. `ip` is kept up to date with the innermost frame's
+    /// instruction pointer, which is otherwise only saved in the frame on calls.
+    unsafe fn execute(&mut self, ip: &mut *const u8) -> RunResult<Value> {
+        let mut frame = *self.frames.last().ok_or(RuntimeError::StackUnderflow)?;
+        let mut chunk = frame.chunk();
+        *ip = frame.ip;
+
+        loop {
+            // Everything live is reachable between instructions.
+            if self.heap.should_collect() {
+                self.collect_garbage()?;
+            }
+
+            let op: Op;
+            if self.tracing.trace_exec {
+                let pos = (*ip as usize) - (chunk.code_ptr() as usize);
+                op = Op::read_and_advance(ip);
+                self.trace(chunk, &op, pos)?;
+            } else {
+                op = Op::read_and_advance(ip);
+            }
+
+            match op {
+                Op::Return => {
+                    let result = self.stack.pop()?;
+                    self.close_upvalues(frame.slots)?;
+                    self.frames.pop();
+                    self.stack.truncate(frame.slots);
+                    match self.frames.last() {
+                        None => return Ok(result),
+                        Some(caller) => {
+                            self.stack.push(result);
+                            frame = *caller;
+                            chunk = frame.chunk();
+                            *ip = frame.ip;
+                        }
                     }
-                    Op::ConstSmol(val_index) => {
-                        let value = self.chunk.get_constant(val_index.into());
-                        self.stack.push(*value);
+                }
+                Op::Call(arg_count) => {
+                    if let Some(caller) = self.frames.last_mut() {
+                        caller.ip = *ip;
                     }
-                    Op::ConstThicc(val_index) => {
-                        let value = self.chunk.get_constant(val_index.into());
-                        self.stack.push(*value);
+                    let callee = *self.stack.peek_at(arg_count.into())?;
+                    self.call_value(callee, arg_count)?;
+                    frame = *self.frames.last().ok_or(RuntimeError::StackUnderflow)?;
+                    chunk = frame.chunk();
+                    *ip = frame.ip;
+                }
+                Op::Closure(val_index) => {
+                    let function = match chunk.get_constant(val_index.into()) {
+                        Value::Obj(handle) => *handle,
+                        _ => panic!("Corrupt bytecode"),
+                    };
+                    let refs = match function.get_unchecked() {
+                        Object::Function(function) => &function.upvalues,
+                        _ => panic!("Corrupt bytecode"),
+                    };
+                    let upvalues = refs
+                        .iter()
+                        .map(|upvalue| {
+                            if upvalue.is_local {
+                                self.capture_upvalue(frame.slots + usize::from(upvalue.index))
+                            } else {
+                                frame.closure().upvalues[usize::from(upvalue.index)]
+                            }
+                        })
+                        .collect();
+                    let closure = self
+                        .heap
+                        .insert_temp(Object::Closure(Closure { function, upvalues }));
+                    self.stack.push(Value::Obj(closure));
+                }
+                Op::GetUpvalue(index) => {
+                    let upvalue = frame.closure().upvalues[usize::from(index)];
+                    let value = self.read_upvalue(upvalue)?;
+                    self.stack.push(value);
+                }
+                Op::SetUpvalue(index) => {
+                    let upvalue = frame.closure().upvalues[usize::from(index)];
+                    let value = *self.stack.peek()?;
+                    self.write_upvalue(upvalue, value)?;
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1)?;
+                    self.stack.pop()?;
+                }
+                Op::Class(name_index) => {
+                    let name = VM::read_name(chunk, name_index).to_string();
+                    let class = self.heap.insert_temp(Object::Class(Class {
+                        name,
+                        methods: HashMap::new(),
+                    }));
+                    self.stack.push(Value::Obj(class));
+                }
+                Op::GetProperty(name_index) => {
+                    let name = VM::read_name(chunk, name_index);
+                    let instance = VM::as_instance(*self.stack.peek()?)?;
+                    let value = match instance.fields.get(name) {
+                        Some(value) => *value,
+                        None => self.bind_method(instance.class, name)?,
+                    };
+                    self.stack.pop()?;
+                    self.stack.push(value);
+                }
+                Op::SetProperty(name_index) => {
+                    let name = VM::read_name(chunk, name_index);
+                    let receiver = *self.stack.peek_at(1)?;
+                    let instance = VM::as_instance(receiver)?;
+                    let handle = receiver.as_object().expect("Instances are objects");
+                    let value = self.stack.pop()?;
+                    self.grow(handle, || {
+                        instance.fields.insert(name.to_string(), value);
+                    });
+                    self.stack.pop()?;
+                    self.stack.push(value);
+                }
+                Op::Method(name_index) => {
+                    let name = VM::read_name(chunk, name_index);
+                    let method = match self.stack.pop()? {
+                        Value::Obj(method) => method,
+                        _ => panic!("Corrupt bytecode"),
+                    };
+                    match *self.stack.peek()? {
+                        Value::Obj(class) => self.grow(class, || {
+                            VM::as_class(class).methods.insert(name.to_string(), method);
+                        }),
+                        _ => panic!("Corrupt bytecode"),
+                    }
+                }
+                Op::Invoke(name_index, arg_count) => {
+                    if let Some(caller) = self.frames.last_mut() {
+                        caller.ip = *ip;
+                    }
+                    self.invoke(VM::read_name(chunk, name_index), arg_count)?;
+                    frame = *self.frames.last().ok_or(RuntimeError::StackUnderflow)?;
+                    chunk = frame.chunk();
+                    *ip = frame.ip;
+                }
+                Op::Inherit => {
+                    let superclass = match *self.stack.peek_at(1)? {
+                        Value::Obj(handle)
+                            if matches!(*handle.get_unchecked(), Object::Class(_)) =>
+                        {
+                            handle
+                        }
+                        value => return Err(RuntimeError::SuperclassNotClass(value)),
+                    };
+                    let subclass = match self.stack.pop()? {
+                        Value::Obj(class) => class,
+                        _ => panic!("Corrupt bytecode"),
+                    };
+                    // Copy down, so method lookup never has to walk the hierarchy.
+                    let methods = VM::as_class(superclass).methods.clone();
+                    self.grow(subclass, || VM::as_class(subclass).methods.extend(methods));
+                }
+                Op::GetSuper(name_index) => {
+                    let name = VM::read_name(chunk, name_index);
+                    let superclass = match self.stack.pop()? {
+                        Value::Obj(class) => class,
+                        _ => panic!("Corrupt bytecode"),
+                    };
+                    let bound = self.bind_method(superclass, name)?;
+                    self.stack.pop()?;
+                    self.stack.push(bound);
+                }
+                Op::SuperInvoke(name_index, arg_count) => {
+                    if let Some(caller) = self.frames.last_mut() {
+                        caller.ip = *ip;
                     }
-                    Op::Negate => self.op_unary(Value::negate)?,
-                    Op::Add => self.op_binary(Value::add)?,
-                    Op::Subtract => self.op_binary(Value::subtract)?,
-                    Op::Multiply => self.op_binary(Value::multiply)?,
-                    Op::Divide => self.op_binary(Value::divide)?,
-                    Op::Nil => self.stack.push(Value::Nil),
-                    Op::True => self.stack.push(Value::Bool(true)),
-                    Op::False => self.stack.push(Value::Bool(false)),
-                    Op::Not => self.op_unary(Value::not)?,
-                    Op::Equal => self.op_binary(Value::equal)?,
-                    Op::Greater => self.op_binary(Value::greater)?,
-                    Op::Less => self.op_binary(Value::less)?,
-                    Op::Print => self.eff(|_heap, val| Ok(println!("{}", val)))?,
-                    Op::Pop => {
-                        self.stack.pop()?;
+                    let superclass = match self.stack.pop()? {
+                        Value::Obj(class) => class,
+                        _ => panic!("Corrupt bytecode"),
+                    };
+                    self.invoke_from_class(
+                        superclass,
+                        VM::read_name(chunk, name_index),
+                        arg_count,
+                    )?;
+                    frame = *self.frames.last().ok_or(RuntimeError::StackUnderflow)?;
+                    chunk = frame.chunk();
+                    *ip = frame.ip;
+                }
+                Op::ConstSmol(val_index) => {
+                    let value = chunk.get_constant(val_index.into());
+                    self.stack.push(*value);
+                }
+                Op::ConstThicc(val_index) => {
+                    let value = chunk.get_constant(val_index.into());
+                    self.stack.push(*value);
+                }
+                Op::Negate => self.op_unary(Value::negate)?,
+                Op::Add => self.op_binary(Value::add)?,
+                Op::Subtract => self.op_binary(Value::subtract)?,
+                Op::Multiply => self.op_binary(Value::multiply)?,
+                Op::Divide => self.op_binary(Value::divide)?,
+                Op::Nil => self.stack.push(Value::Nil),
+                Op::True => self.stack.push(Value::Bool(true)),
+                Op::False => self.stack.push(Value::Bool(false)),
+                Op::Not => self.op_unary(Value::not)?,
+                Op::Equal => self.op_binary(Value::equal)?,
+                Op::Greater => self.op_binary(Value::greater)?,
+                Op::Less => self.op_binary(Value::less)?,
+                Op::Print => {
+                    let value = self.stack.pop()?;
+                    writeln!(self.out, "{}", value)?;
+                }
+                Op::Pop => {
+                    self.stack.pop()?;
+                }
+                Op::DefineGlobal(name_index) => {
+                    let name = VM::read_name(chunk, name_index);
+                    let value = self.stack.pop()?;
+                    self.globals.insert(name.to_string(), value);
+                }
+                Op::GetGlobal(name_index) => {
+                    let name = VM::read_name(chunk, name_index);
+                    match self.globals.get(name) {
+                        Some(value) => self.stack.push(*value),
+                        None => return Err(RuntimeError::UndefinedVariable(name.to_string())),
                     }
                 }
+                Op::SetGlobal(name_index) => {
+                    let name = VM::read_name(chunk, name_index);
+                    let value = *self.stack.peek()?;
+                    match self.globals.get_mut(name) {
+                        // Assignment is an expression, so the value stays on the stack.
+                        Some(slot) => *slot = value,
+                        None => return Err(RuntimeError::UndefinedVariable(name.to_string())),
+                    }
+                }
+                Op::GetLocal(slot) => {
+                    let value = self.stack.get(frame.slots + usize::from(slot))?;
+                    self.stack.push(value);
+                }
+                Op::SetLocal(slot) => {
+                    let value = *self.stack.peek()?;
+                    self.stack.set(frame.slots + usize::from(slot), value)?;
+                }
+                Op::Jump(offset) => {
+                    *ip = ip.add(offset.into());
+                }
+                Op::JumpIfFalse(offset) => {
+                    if !self.stack.peek()?.truthy() {
+                        *ip = ip.add(offset.into());
+                    }
+                }
+                Op::Loop(offset) => {
+                    *ip = ip.sub(offset.into());
+                }
             }
         }
     }
 }
 
 #[derive(Default)]
-struct Stack(Vec<Value>);
+pub struct Stack(Vec<Value>);
 impl Stack {
     #[inline]
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
 
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len)
+    }
+
     #[inline]
     pub fn push(&mut self, value: Value) {
         self.0.push(value)
@@ -179,6 +1036,55 @@ impl Stack {
     fn peek(&self) -> RunResult<&Value> {
         self.0.last().ok_or(RuntimeError::StackUnderflow)
     }
+
+    #[inline]
+    /// The top `count` values on the stack, deepest first.
+    fn top(&self, count: usize) -> RunResult<&[Value]> {
+        let len = self.0.len();
+        if count <= len {
+            Ok(&self.0[len - count..])
+        } else {
+            Err(RuntimeError::StackUnderflow)
+        }
+    }
+
+    #[inline]
+    /// Look `distance` values down from the top of the stack.
+    fn peek_at(&self, distance: usize) -> RunResult<&Value> {
+        let len = self.0.len();
+        if distance < len {
+            Ok(&self.0[len - 1 - distance])
+        } else {
+            Err(RuntimeError::StackUnderflow)
+        }
+    }
+
+    #[inline]
+    /// Overwrite the value `distance` values down from the top of the stack.
+    fn set_from_top(&mut self, distance: usize, value: Value) -> RunResult<()> {
+        let len = self.0.len();
+        if distance < len {
+            self.0[len - 1 - distance] = value;
+            Ok(())
+        } else {
+            Err(RuntimeError::StackUnderflow)
+        }
+    }
+
+    #[inline]
+    fn get(&self, slot: usize) -> RunResult<Value> {
+        self.0
+            .get(slot)
+            .copied()
+            .ok_or(RuntimeError::StackUnderflow)
+    }
+
+    #[inline]
+    fn set(&mut self, slot: usize, value: Value) -> RunResult<()> {
+        let target = self.0.get_mut(slot).ok_or(RuntimeError::StackUnderflow)?;
+        *target = value;
+        Ok(())
+    }
 }
 impl Display for Stack {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
@@ -186,3 +1092,701 @@ impl Display for Stack {
         self.0.iter().try_for_each(|v| write!(f, "[ {} ]", v))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use super::{InterpretError, RuntimeError, Tracing, FRAMES_MAX, VM};
+    use crate::compiler::{CompileError, SyntaxError};
+    use crate::gc::HeapStats;
+    use crate::scanner::{ScanError, ScanErrorValue};
+    use crate::value::{TypeError, Value};
+
+    fn interpret(src: &str) -> (VM, Result<(), InterpretError>) {
+        let mut vm = VM::new();
+        let result = vm.interpret(src);
+        (vm, result)
+    }
+
+    /// A sink whose contents can still be read once it's been handed off.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn runtime_error(result: Result<(), InterpretError>) -> Option<RuntimeError> {
+        match result {
+            Err(InterpretError::Runtime { error, .. }) => Some(error),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn globals_survive_across_statements() {
+        let (vm, result) = interpret("var a = 1; var b; a = a + 2; b = a * 2;");
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Double(3.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Double(6.0)));
+    }
+
+    #[test]
+    fn objects_survive_across_interpretations() {
+        let (mut vm, result) = interpret("var a = \"a\" + \"b\";");
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.interpret("var b = a + \"c\";"), Ok(()));
+        match vm.globals.get("b") {
+            Some(Value::Obj(handle)) => assert!(matches!(
+                unsafe { handle.get_unchecked() },
+                crate::value::Object::Str(s) if s == "abc"
+            )),
+            other => panic!("Expected a string, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn locals_shadow_and_go_out_of_scope() {
+        let (vm, result) = interpret(
+            "var a = 1; var b; { var a = 10; { var c = a + 1; a = c; } b = a; } a = a + b;",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Double(12.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn if_else_takes_one_branch() {
+        let (vm, result) = interpret(
+            "var a; var b; if (1 < 2) a = 1; else a = 2; if (nil) { b = 1; } else { b = 2; }",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Double(1.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Double(2.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn while_and_for_loops_iterate() {
+        let (vm, result) = interpret(
+            "var a = 0; while (a < 5) a = a + 1;
+             var b = 0; for (var i = 0; i < 4; i = i + 1) { var j = i; b = b + j; }
+             var c = 1; for (; c < 100;) c = c * 2;",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Double(5.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Double(6.0)));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Double(128.0)));
+        assert_eq!(vm.globals.get("i"), None);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn and_or_short_circuit_to_the_deciding_operand() {
+        let (vm, result) = interpret(
+            "var a = nil and undefined; var b = 1 and 2; var c = nil or 3;
+             var d = \"s\" or undefined; var e = false or nil and undefined;
+             var f = !0;",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Nil));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Double(2.0)));
+        assert_eq!(vm.globals.get("c"), Some(&Value::Double(3.0)));
+        assert!(matches!(vm.globals.get("d"), Some(Value::Obj(_))));
+        assert_eq!(vm.globals.get("e"), Some(&Value::Nil));
+        assert_eq!(vm.globals.get("f"), Some(&Value::Bool(false)));
+    }
+
+    #[test]
+    fn functions_call_and_return() {
+        let (vm, result) = interpret(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+             fun nothing() {}
+             var a = fib(10); var b = nothing();",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Double(55.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Nil));
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn closures_share_captured_variables_beyond_their_scope() {
+        let (vm, result) = interpret(
+            "var inc; var get;
+             fun make() {
+               var count = 0;
+               fun i() { count = count + 1; }
+               fun g() { return count; }
+               inc = i; get = g;
+             }
+             make(); inc(); inc();
+             var a = get();
+             fun outer() { var x = 1; fun middle() { fun inner() { return x; } return inner; } return middle; }
+             var b = outer()()();",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Double(2.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Double(1.0)));
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn instances_hold_fields() {
+        let (vm, result) = interpret(
+            "class Point {}
+             var p = Point();
+             p.x = 1; p.y = p.x + 1;
+             p.next = Point(); p.next.x = 10;
+             var a = p.y; var b = p.next.x;",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Double(2.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Double(10.0)));
+    }
+
+    #[test]
+    fn methods_bind_this_and_initializers_run() {
+        let (vm, result) = interpret(
+            "class Counter {
+               init(start) { this.n = start; }
+               inc() { this.n = this.n + 1; return this; }
+               get() { return this.n; }
+             }
+             var c = Counter(5);
+             c.inc().inc();
+             var bound = c.get;
+             var a = bound();
+             var b = c.init(1) == c;",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Double(7.0)));
+        assert_eq!(vm.globals.get("b"), Some(&Value::Bool(true)));
+
+        let (_, arity) = interpret("class C { init(a) {} } C();");
+        assert_eq!(
+            runtime_error(arity),
+            Some(RuntimeError::ArityMismatch {
+                expected: 1,
+                actual: 0
+            })
+        );
+    }
+
+    #[test]
+    fn subclasses_inherit_methods_and_call_super() {
+        let (vm, result) = interpret(
+            "class A {
+               init(n) { this.n = n; }
+               name() { return \"A\"; }
+               twice() { return this.n * 2; }
+             }
+             class B < A {
+               init(n) { super.init(n + 1); }
+               name() { return \"B\"; }
+               parent() { var f = super.name; return f(); }
+             }
+             var b = B(1);
+             var a = b.twice(); var own = b.name(); var parent = b.parent();",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("a"), Some(&Value::Double(4.0)));
+        let strings: Vec<String> = ["own", "parent"]
+            .iter()
+            .map(|name| vm.globals.get(*name).unwrap().to_string())
+            .collect();
+        assert_eq!(strings, vec!["\"B\"", "\"A\""]);
+
+        let (_, not_class) = interpret("var A = 1; class B < A {}");
+        assert_eq!(
+            runtime_error(not_class),
+            Some(RuntimeError::SuperclassNotClass(Value::Double(1.0)))
+        );
+    }
+
+    #[test]
+    fn natives_are_called_with_their_arguments() {
+        let mut vm = VM::new();
+        vm.define_native("add", 2, |_, args| match args {
+            [Value::Double(a), Value::Double(b)] => Ok(Value::Double(a + b)),
+            _ => Err(RuntimeError::UndefinedVariable("numbers".to_string())),
+        });
+        let result = vm.interpret(
+            "var sum = add(1, add(2, 3));
+             var elapsed = clock();
+             var later = clock() >= elapsed;",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Double(6.0)));
+        assert_eq!(vm.globals.get("later"), Some(&Value::Bool(true)));
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(
+            runtime_error(vm.interpret("add(1);")),
+            Some(RuntimeError::ArityMismatch {
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert_eq!(
+            runtime_error(vm.interpret("add(nil, 1);")),
+            Some(RuntimeError::UndefinedVariable("numbers".to_string()))
+        );
+    }
+
+    #[test]
+    fn arguments_are_exposed_to_scripts() {
+        let mut vm = VM::new();
+        vm.define_args(vec!["x".to_string()]);
+        let result = vm.interpret(
+            "var count = argCount();
+             var first = arg(0) == \"x\";
+             var past = arg(1);",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("count"), Some(&Value::Double(1.0)));
+        assert_eq!(vm.globals.get("first"), Some(&Value::Bool(true)));
+        assert_eq!(vm.globals.get("past"), Some(&Value::Nil));
+        assert_eq!(
+            runtime_error(vm.interpret("arg(nil);")),
+            Some(RuntimeError::Type(TypeError::NotANumber(Value::Nil)))
+        );
+    }
+
+    /// A sink which can't be written to.
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn print_writes_to_the_output() {
+        let out = SharedBuffer::default();
+        let mut vm = VM::new().with_output(Box::new(out.clone()));
+        assert_eq!(vm.interpret("print 1 + 2; print \"three\";"), Ok(()));
+        assert_eq!(out.contents(), "3\n\"three\"\n");
+
+        let mut vm = VM::new().with_output(Box::new(Closed));
+        assert_eq!(
+            runtime_error(vm.interpret("print 1;")),
+            Some(RuntimeError::Output(io::ErrorKind::BrokenPipe))
+        );
+    }
+
+    #[test]
+    fn reset_forgets_globals_but_keeps_the_output() {
+        let out = SharedBuffer::default();
+        let mut vm = VM::new().with_output(Box::new(out.clone()));
+        assert_eq!(vm.interpret("var a = 1;"), Ok(()));
+        vm.reset();
+        assert_eq!(vm.globals.get("a"), None);
+        assert!(vm.globals.contains_key("clock"));
+        assert_eq!(vm.interpret("print 2;"), Ok(()));
+        assert_eq!(out.contents(), "2\n");
+    }
+
+    #[test]
+    fn failed_runs_leave_their_stack_for_inspection() {
+        let (mut vm, result) = interpret("var a = 1; { var b = \"kept\"; b + nil; }");
+        assert!(runtime_error(result).is_some());
+        assert!(vm.stack().to_string().contains("[ \"kept\" ]"));
+
+        assert_eq!(vm.interpret("var c = 2;"), Ok(()));
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn tracing_is_written_to_the_sink_only_when_asked_for() {
+        let sink = SharedBuffer::default();
+        let mut vm = VM::new().with_tracing(Tracing::default(), Some(Box::new(sink.clone())));
+        assert_eq!(vm.interpret("var a = 1;"), Ok(()));
+        assert_eq!(sink.contents(), "");
+
+        let tracing = Tracing {
+            print_code: true,
+            trace_exec: true,
+            ..Tracing::default()
+        };
+        let mut vm = VM::new().with_tracing(tracing, Some(Box::new(sink.clone())));
+        assert_eq!(vm.interpret("var a = 1;"), Ok(()));
+        let trace = sink.contents();
+        assert!(trace.starts_with("==          <script>           ==\n"));
+        assert!(trace.contains("          [ <script> ][ 1 ]\n"));
+        assert_eq!(trace.matches("OP_DEFINE_GLOBAL").count(), 2);
+    }
+
+    #[test]
+    fn garbage_is_collected_and_everything_else_survives() {
+        let (mut vm, result) = interpret(
+            "class Box {
+               init(value) { this.value = value; }
+               get() { return this.value; }
+             }
+             fun counter() {
+               var n = 0;
+               fun increment() { n = n + 1; return n; }
+               return increment;
+             }
+             var box = Box(\"kept\");
+             var increment = counter();
+             var s;
+             for (var i = 0; i < 30000; i = i + 1) {
+               s = \"a\" + \"b\";
+               increment();
+             }
+             var same = box.get() + s == \"keptab\";",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("same"), Some(&Value::Bool(true)));
+        assert!(vm.heap_stats().objects() < 30000);
+
+        vm.collect_garbage().unwrap();
+        assert_eq!(
+            vm.interpret("var count = increment(); var again = box.get() == \"kept\";"),
+            Ok(())
+        );
+        assert_eq!(vm.globals.get("count"), Some(&Value::Double(30001.0)));
+        assert_eq!(vm.globals.get("again"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn stress_mode_collects_after_every_allocation() {
+        let mut vm = VM::new().with_gc_stress(true);
+        let result = vm.interpret(
+            "class Pair {
+               init(a, b) { this.a = a; this.b = b; }
+               join() { return this.a + this.b; }
+             }
+             fun prefixer(prefix) {
+               fun add(s) { return prefix + s; }
+               return add;
+             }
+             var add = prefixer(\"pre\");
+             var joined = \"\";
+             for (var i = 0; i < 20; i = i + 1) {
+               joined = Pair(add(\"fix\"), \"es\").join();
+             }
+             var same = joined == \"prefixes\";",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.globals.get("same"), Some(&Value::Bool(true)));
+        // Each time round the loop allocates two strings and an instance.
+        assert!(vm.heap_stats().collections >= 60);
+    }
+
+    #[test]
+    fn objects_growing_are_counted_against_the_heap() {
+        let (mut vm, result) = interpret(
+            "class A { a() {} b() {} c() {} }
+             class B < A { d() {} }
+             var list = nil;
+             for (var i = 0; i < 100; i = i + 1) {
+               var node = B();
+               node.next = list;
+               node.x = 1; node.y = 2; node.z = 3; node.w = 4;
+               list = node;
+             }",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.heap.bytes(), vm.heap.measure());
+        let bytes = vm.heap.bytes();
+        vm.collect_garbage().unwrap();
+        assert!(vm.heap.bytes() <= bytes);
+        assert_eq!(vm.heap.bytes(), vm.heap.measure());
+    }
+
+    #[test]
+    fn long_chains_of_objects_are_marked_without_recursing() {
+        // Building the list under stress would collect once per node, so only
+        // collect once it's built, starting from its far end.
+        let mut vm = VM::new().with_gc_stress(false);
+        let result = vm.interpret(
+            "class Node { init(next) { this.next = next; } }
+             var list = nil;
+             for (var i = 0; i < 100000; i = i + 1) list = Node(list);",
+        );
+        assert_eq!(result, Ok(()));
+
+        vm.heap.set_stress(true);
+        let collections = vm.heap_stats().collections;
+        let result = vm.interpret(
+            "var length = 0;
+             for (var node = list; node != nil; node = node.next) length = length + 1;",
+        );
+        assert_eq!(result, Ok(()));
+        assert!(vm.heap_stats().collections > collections);
+        assert_eq!(vm.globals.get("length"), Some(&Value::Double(100000.0)));
+    }
+
+    #[test]
+    fn gc_log_reports_each_collection() {
+        let sink = SharedBuffer::default();
+        let tracing = Tracing {
+            gc_log: true,
+            ..Tracing::default()
+        };
+        let mut vm = VM::new()
+            .with_tracing(tracing, Some(Box::new(sink.clone())))
+            .with_gc_stress(true);
+        assert_eq!(vm.interpret("var s = \"a\" + \"b\";"), Ok(()));
+        let log = sink.contents();
+        let collections = vm.heap_stats().collections;
+        assert!(collections > 0);
+        assert_eq!(log.lines().count(), collections);
+        assert!(log.lines().all(|line| line.starts_with("-- gc: ")));
+    }
+
+    #[test]
+    fn heap_stats_count_each_kind_of_object() {
+        let (mut vm, result) = interpret(
+            "class A {}
+             var a = A();
+             var b = A();
+             fun f() {}",
+        );
+        assert_eq!(result, Ok(()));
+        vm.collect_garbage().unwrap();
+        let stats = vm.heap_stats();
+        assert_eq!(
+            stats,
+            HeapStats {
+                // The script and `f`.
+                functions: 2,
+                closures: 1,
+                classes: 1,
+                instances: 2,
+                // `clock` and `gcStats`.
+                natives: 2,
+                ..stats
+            }
+        );
+
+        assert_eq!(
+            vm.interpret("var stats = gcStats(); var instances = stats.instances;"),
+            Ok(())
+        );
+        assert_eq!(vm.globals.get("instances"), Some(&Value::Double(2.0)));
+    }
+
+    #[test]
+    fn runtime_errors_trace_the_failing_lines() {
+        let out = SharedBuffer::default();
+        let mut vm = VM::new().with_output(Box::new(out.clone()));
+        let result = vm.interpret(
+            "fun inner(a) {
+               return -a;
+             }
+             fun outer() {
+               var x = nil;
+               return inner(x);
+             }
+             print 1;
+             outer();",
+        );
+        let (error, span, trace) = match result {
+            Err(InterpretError::Runtime {
+                error, span, trace, ..
+            }) => (error, span, trace),
+            other => panic!("Expected a runtime error, got {:?}", other),
+        };
+        assert_eq!(error, RuntimeError::Type(TypeError::NotANumber(Value::Nil)));
+        // The `-` of `-a`.
+        assert_eq!(
+            (span.start.line, span.start.column, span.length),
+            (1, 22, 1)
+        );
+        let frames: Vec<(Option<&str>, usize)> = trace
+            .iter()
+            .map(|frame| (frame.function.as_deref(), frame.span.start.line))
+            .collect();
+        assert_eq!(
+            frames,
+            vec![(Some("inner"), 1), (Some("outer"), 5), (None, 8)]
+        );
+        assert_eq!(trace[0].to_string(), "[line 2] in inner()");
+        assert_eq!(out.contents(), "1\n");
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn recursive_frames_are_merged_in_traces() {
+        let (_, result) = interpret(
+            "fun forever(n) {
+               return forever(n + 1);
+             }
+             forever(0);",
+        );
+        let (error, trace) = match result {
+            Err(InterpretError::Runtime { error, trace, .. }) => (error, trace),
+            other => panic!("Expected a runtime error, got {:?}", other),
+        };
+        assert_eq!(error, RuntimeError::StackOverflow);
+        let lines: Vec<String> = trace.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            vec![
+                format!(
+                    "[line 2] in forever() (repeated {} more times)",
+                    FRAMES_MAX - 2
+                ),
+                "[line 4] in script".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_errors_are_all_collected_and_nothing_runs() {
+        let (vm, result) = interpret(
+            "var a = 1;
+             print;
+             var b = @;
+             class A < A {}
+             fun f() { super.g(); }
+             var c = 3;",
+        );
+        let errors = match result {
+            Err(InterpretError::Compile(errors)) => errors,
+            other => panic!("Expected compile errors, got {:?}", other),
+        };
+        assert_eq!(errors.len(), 5);
+        assert!(matches!(
+            errors[1],
+            CompileError::Scan(ScanError {
+                value: ScanErrorValue::UnexpectedCharacter('@'),
+                ..
+            })
+        ));
+        assert!(matches!(
+            &errors[3],
+            CompileError::Syntax(SyntaxError::InheritFromSelf { span, name })
+                if span.start.line == 3 && name == "A"
+        ));
+        assert!(matches!(
+            errors[4],
+            CompileError::Syntax(SyntaxError::SuperOutsideClass { .. })
+        ));
+        assert!(!vm.globals.contains_key("a"));
+    }
+
+    #[test]
+    fn state_persists_across_interpret_calls() {
+        let mut vm = VM::new();
+        let lines = [
+            "fun add(a, b) { return a + b; }",
+            "class Pair { init(a, b) { this.a = a; this.b = b; } sum() { return add(this.a, this.b); } }",
+            "var greeting = \"hi\";",
+            "var get; { var x = 1; fun g() { return x; } get = g; nil(); }",
+            "var sum = Pair(1, 2).sum(); var captured = get(); var same = greeting == \"hi\";",
+        ];
+        let results: Vec<bool> = lines
+            .iter()
+            .map(|line| vm.interpret(line).is_ok())
+            .collect();
+        assert_eq!(results, vec![true, true, true, false, true]);
+        assert_eq!(vm.globals.get("sum"), Some(&Value::Double(3.0)));
+        assert_eq!(vm.globals.get("captured"), Some(&Value::Double(1.0)));
+        assert_eq!(vm.globals.get("same"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn evaluate_produces_a_trailing_expression() {
+        let mut vm = VM::new();
+        let mut repr = |src| vm.evaluate(src).map(|value| value.repr());
+        assert_eq!(repr("1 + 2"), Ok("3".to_string()));
+        assert_eq!(repr("var a = 2; a * 4;"), Ok("8".to_string()));
+        assert_eq!(repr("a; var b = 1;"), Ok("nil".to_string()));
+        assert_eq!(repr("{ a; }"), Ok("nil".to_string()));
+        assert_eq!(repr("\"two\nlines\""), Ok("\"two\\nlines\"".to_string()));
+        assert_eq!(
+            repr("class P {} var p = P(); p.y = \"b\"; p.x = 1; p"),
+            Ok("P { x: 1, y: \"b\" }".to_string())
+        );
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn unbraced_bodies_are_never_the_trailing_expression() {
+        let mut vm = VM::new();
+        assert_eq!(vm.evaluate("var i = 0;"), Ok(Value::Nil));
+        assert_eq!(vm.evaluate("while (i < 3) i = i + 1;"), Ok(Value::Nil));
+        assert_eq!(vm.globals.get("i"), Some(&Value::Double(3.0)));
+        assert_eq!(vm.evaluate("if (false) 1; else 2;"), Ok(Value::Nil));
+        assert_eq!(vm.evaluate("for (; i < 5;) i = i + 1;"), Ok(Value::Nil));
+        assert_eq!(vm.globals.get("i"), Some(&Value::Double(5.0)));
+    }
+
+    #[test]
+    fn bad_property_accesses_are_runtime_errors() {
+        let (_, undefined) = interpret("class C {} C().nope;");
+        assert_eq!(
+            runtime_error(undefined),
+            Some(RuntimeError::UndefinedProperty("nope".to_string()))
+        );
+
+        let (_, not_instance) = interpret("var a = 1; a.b = 2;");
+        assert_eq!(
+            runtime_error(not_instance),
+            Some(RuntimeError::NotAnInstance(Value::Double(1.0)))
+        );
+    }
+
+    #[test]
+    fn bad_calls_are_runtime_errors() {
+        let (_, arity) = interpret("fun f(a, b) {} f(1);");
+        assert_eq!(
+            runtime_error(arity),
+            Some(RuntimeError::ArityMismatch {
+                expected: 2,
+                actual: 1
+            })
+        );
+
+        let (_, not_callable) = interpret("true();");
+        assert_eq!(
+            runtime_error(not_callable),
+            Some(RuntimeError::NotCallable(Value::Bool(true)))
+        );
+
+        let (vm, overflow) = interpret("fun f() { f(); } f();");
+        assert_eq!(runtime_error(overflow), Some(RuntimeError::StackOverflow));
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn undefined_globals_are_runtime_errors() {
+        let (_, read) = interpret("print nope;");
+        assert_eq!(
+            runtime_error(read),
+            Some(RuntimeError::UndefinedVariable("nope".to_string()))
+        );
+
+        let (_, assign) = interpret("nope = 1;");
+        assert_eq!(
+            runtime_error(assign),
+            Some(RuntimeError::UndefinedVariable("nope".to_string()))
+        );
+    }
+}